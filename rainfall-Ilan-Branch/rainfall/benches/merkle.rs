@@ -0,0 +1,40 @@
+use std::thread;
+use criterion::{black_box,criterion_group,criterion_main,BenchmarkId,Criterion};
+use rand::RngCore;
+use rainfall::merkle::MerkleTree;
+
+/*size in bytes of the leaves, about the size of a serialized payload */
+const LEAF_LEN: usize = 128;
+
+fn random_leaves(n: usize) -> Vec<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    (0..n).map(|_| {
+        let mut leaf = vec![0u8;LEAF_LEN];
+        rng.fill_bytes(&mut leaf);
+        leaf
+    }).collect()
+}
+
+pub fn merkle_benchmark(c: &mut Criterion) {
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut group = c.benchmark_group("merkle tree");
+
+    for log_size in [16, 20] {
+        let leaves = random_leaves(1 << log_size);
+        let slices: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
+
+        group.bench_with_input(BenchmarkId::new("new", log_size), &slices, |b, slices| b.iter(|| MerkleTree::new(black_box(slices))));
+        group.bench_with_input(BenchmarkId::new("new_parallel 1 thread", log_size), &slices, |b, slices| b.iter(|| MerkleTree::new_parallel(black_box(slices), 1)));
+        if threads > 1 {
+            group.bench_with_input(BenchmarkId::new(format!("new_parallel {} threads",threads), log_size), &slices, |b, slices| b.iter(|| MerkleTree::new_parallel(black_box(slices), threads)));
+        }
+    }
+    group.finish();
+}
+
+criterion_group!{
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = merkle_benchmark
+}
+criterion_main!(benches);
@@ -4,10 +4,7 @@ use blst::min_pk::{AggregatePublicKey, AggregateSignature, PublicKey, SecretKey,
 use libc::tm;
 use rand::{seq::SliceRandom, Rng, RngCore};
 use criterion::{black_box,criterion_group,criterion_main,Criterion};
-use rainfall::{batch, merkle::{self, MerkleTree}};
-
-
-const BATCH_SIZE:usize = 1<<16;
+use rainfall::{batch, config::BatchConfig, merkle::{self, MerkleTree}, signing::DST};
 
 fn generate_key_pair() -> (SecretKey,PublicKey) {
     let mut rng = rand::thread_rng();
@@ -21,14 +18,13 @@ fn generate_key_pair() -> (SecretKey,PublicKey) {
 fn verify_sig(aggr_key: PublicKey,aggr_sig: Signature) {
     let k = aggr_key;
     let sig = aggr_sig;
-    let dst = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_NUL_";
-    let res = sig.verify(true, b"msgtobesigned", dst, &[], &k, true);
+    let res = sig.verify(true, b"msgtobesigned", DST, &[], &k, true);
 }
 
 fn multiple_go_pks(list_pks: &Vec<&PublicKey>) {
     let first = list_pks[0];
     let mut start = AggregatePublicKey::from_public_key(first);
-    for i in 1..BATCH_SIZE {
+    for i in 1..list_pks.len() {
         let pk = list_pks[i];
         start.add_aggregate(&AggregatePublicKey::from_public_key(pk));
     }
@@ -37,7 +33,7 @@ fn multiple_go_pks(list_pks: &Vec<&PublicKey>) {
 fn multiple_go_sigs(list_sigs: &Vec<&Signature>) {
     let first = list_sigs[0];
     let mut start = AggregateSignature::from_signature(first);
-    for i in 1..BATCH_SIZE {
+    for i in 1..list_sigs.len() {
         let sig = list_sigs[i];
         start.add_aggregate(&AggregateSignature::from_signature(sig));
     }
@@ -117,7 +113,6 @@ impl SignatureTree{
     fn check(&self, message: &[u8]) -> Vec<usize> {
         
         let now: SystemTime = SystemTime::now();
-        let dst = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_NUL_";
         let mut stack:Vec<&NodeT> = Vec::new();
         let root = self.root.as_ref().unwrap();
         stack.push(root);
@@ -129,7 +124,7 @@ impl SignatureTree{
             let curr = stack.pop().expect("failed to unwrap");
             let sig = curr.tuple.0.to_signature();
             let pk = curr.tuple.1.to_public_key();
-            match sig.verify(true, message, dst, &[], &pk, true) {
+            match sig.verify(true, message, DST, &[], &pk, true) {
                 blst::BLST_ERROR::BLST_SUCCESS => {
                 },
                 blst::BLST_ERROR::BLST_VERIFY_FAIL => {
@@ -164,7 +159,6 @@ impl SignatureTree{
 fn binary_search(list_pks: &[&PublicKey], list_sigs: &[&Signature], message: &[u8]) -> Vec<usize>{ 
     assert!(list_sigs.len() == list_pks.len());
     let now: SystemTime = SystemTime::now();
-    let dst = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_NUL_";
     let mut stack: Vec<(usize,usize)> = Vec::new();
     stack.push((0,list_pks.len()-1));
     /*Vec to store the indices of the unvalid signatures */
@@ -177,7 +171,7 @@ fn binary_search(list_pks: &[&PublicKey], list_sigs: &[&Signature], message: &[u
         let agg_pk = aggregate_keys_fast(list_pks,lowerbound,upperbound);
         let agg_sigs = aggregate_sigs_fast(list_sigs,lowerbound,upperbound);
         
-        match agg_sigs.verify(true, message, dst, &[], &agg_pk, true) {
+        match agg_sigs.verify(true, message, DST, &[], &agg_pk, true) {
             
             /* Case where the test is successful, this means there are no invalid signatures in this section*/
             blst::BLST_ERROR::BLST_SUCCESS => (),
@@ -205,15 +199,14 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     let mut input= [0u8;128];
     let mut rng = rand::thread_rng();
     rng.fill_bytes(&mut input);
-    let leaves = VecDeque::from((0..BATCH_SIZE).map(|x| Node::new(blake3::hash(&input), None, None)).collect::<Vec<Node>>());
+    let batch_size = BatchConfig::default().batch_size;
     
     let mut pks: Vec<PublicKey> = vec![];
     let mut sigs: Vec<Signature> = vec![];
-    for i in 0..BATCH_SIZE{
+    for i in 0..batch_size{
         let (sk,pk) = generate_key_pair();
-        let dst = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_NUL_";
         let signed;
-        signed = sk.sign(b"msgmsgmsg", dst, &[]);
+        signed = sk.sign(b"msgmsgmsg", DST, &[]);
         sigs.push(signed);
         pks.push(pk);
     }
@@ -9,25 +9,27 @@ use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 use blake3::Hash;
 use core_affinity::CoreId;
-use rainfall::batch::Payload;
-use rainfall::merkle::{verify_merkle_proof, MerklePath};
+use rainfall::batch::{Payload, RootSignature, Submission};
+use rainfall::client::PendingPayloads;
+use rainfall::merkle::MerklePath;
 use blst::min_pk::{SecretKey,PublicKey,Signature};
 use rand::{RngCore,Rng};
+use rainfall::config::BatchConfig;
 use rainfall::recvmessage::RecvMessage;
+use rainfall::rejection::{NoticeError, Rejection, RejectionReason, Resubmit};
+use rainfall::signing::{BrokerId, DST};
 use libc::*;
 use std::thread::{self, JoinHandle};
 
 
 const QUEUE_SIZE:usize = 128;
-const VLEN:c_uint = 1024;
-const BATCH_SIZE: u64 = 1<<16;
 const SPEED: usize = 1<<18;
 const BURST: usize = 100;
 const SECOND: u64 = 1000000000;
-const BUFSIZE: c_uint = 1<<10;
-/*for simplification purposes */
-const FAKE_ROOT: [u8;32] = [200, 117, 111, 57, 59, 197, 34, 95, 163, 98, 125, 151, 19, 45, 52, 158, 129, 137, 
-                            95, 68, 115, 72, 118, 235, 175, 93, 230, 204, 31, 175, 122, 223];
+/*id of the broker the clients submit to, clients refuse to sign batches of any other broker */
+const BROKER_ID: BrokerId = 0;
+/*the notices of the broker are only acted upon if they are signed with its key */
+const BROKER_PK_PATH: &str = "src/keys/broker_pk";
 
 
 fn generate_key_pair( ) -> (SecretKey,PublicKey) {
@@ -40,10 +42,10 @@ fn generate_key_pair( ) -> (SecretKey,PublicKey) {
     (sk,pk)
 }
 
-fn get_sks_from_file() -> Vec<SecretKey>{
-    let mut sks: Vec<SecretKey> = Vec::with_capacity(2 * BATCH_SIZE as usize);
+fn get_sks_from_file(num_keys: usize) -> Vec<SecretKey>{
+    let mut sks: Vec<SecretKey> = Vec::with_capacity(num_keys);
     let mut f = File::open("src/keys/sks").expect("Unable to open file");
-    for i in 0..(2* BATCH_SIZE) {
+    for i in 0..num_keys {
         let mut buf = [0u8;32];
         f.read(&mut buf).expect("failed to read");
         match SecretKey::from_bytes(&buf) {
@@ -54,10 +56,21 @@ fn get_sks_from_file() -> Vec<SecretKey>{
     sks
 }
 
+fn get_broker_pk() -> PublicKey {
+    let mut f = File::open(BROKER_PK_PATH).expect("Unable to open file");
+    let mut buf = [0u8;48];
+    f.read_exact(&mut buf).expect("failed to read");
+    PublicKey::from_bytes(&buf).expect("invalid broker public key")
+}
+
 fn main(){
 
     let args: Vec<String> = env::args().skip(1).collect();
     let client_addr:SocketAddrV4 ;
+    let config = BatchConfig::default();
+    let vlen = config.vlen;
+    /*this process plays the role of enough clients to fill two batches */
+    let num_clients = 2 * config.batch_size;
 
     match args.len() {
         1 => { 
@@ -122,17 +135,18 @@ fn main(){
         sin_port: u16::to_be(10000),
         sin_zero:[0;8],
     };
-    let sks = Arc::new(get_sks_from_file());
-    let mut signed_fake_root: Vec<Signature> = Vec::with_capacity(sks.len());
-    let dst = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_NUL_";
-    for i in 0..sks.len(){
-        let sig = sks[i].sign(&FAKE_ROOT, dst, &[]);
-        signed_fake_root.push(sig);
-    }
-
-    let payloads: Vec<Vec<u8>> = (0..(2 *BATCH_SIZE))
-        .map(|x| Payload::new(x,0,vec![0u8;128]))
-        .map(|p| p.to_bytes())
+    let sks = Arc::new(get_sks_from_file(num_clients));
+    let broker_pk = get_broker_pk();
+
+    /*the payloads are kept until the broker proves they were included in a batch */
+    let mut pending = PendingPayloads::new(BROKER_ID);
+    let payloads: Vec<Vec<u8>> = (0..num_clients as u64)
+        .map(|x| {
+            let p = pending.next_payload(x,vec![0u8;128]);
+            pending.submit(&p);
+            let sk = &sks[p.num_id as usize];
+            Submission::sign(p, sk, BROKER_ID).to_bytes()
+        })
         .collect();
     let p = Arc::new(payloads);
     
@@ -144,7 +158,7 @@ fn main(){
         let p_clone= Arc::clone(&p);
         move || {
             
-            let mut msg = RecvMessage::new();
+            let mut msg = RecvMessage::new(&config);
             
         
             /* for this demo, the broker will send also the 
@@ -156,7 +170,7 @@ fn main(){
             let mut sent: usize = 0;
             let start = SystemTime::now();
         
-            while sent < 2 * BATCH_SIZE as usize { 
+            while sent < num_clients { 
                 let now = SystemTime::now();
                 let elapsed = now.duration_since(start).unwrap();
                 let elapsed_sec = elapsed.as_nanos() as f64 / SECOND as f64;
@@ -169,7 +183,7 @@ fn main(){
                     thread::sleep(Duration::from_nanos(cooldown as u64));
                 }
         
-                allowance = 2 * BATCH_SIZE as usize - sent;
+                allowance = num_clients - sent;
 
                 if allowance > BURST {
                     allowance = BURST;
@@ -178,8 +192,8 @@ fn main(){
                 let mut b = 0;
                 while b < allowance {
                     let mut len = allowance - b;
-                    if len > VLEN as usize {
-                        len = VLEN as usize;
+                    if len > vlen {
+                        len = vlen;
                     }
         
                     if len < payload_slice.len() {
@@ -197,7 +211,7 @@ fn main(){
                         }
                     }
                     
-                    b += VLEN as usize; 
+                    b += vlen; 
                     sent += len;        
                 }
             }
@@ -224,7 +238,7 @@ fn main(){
         move || {
             let mut msg_avail: Vec<RecvMessage> = Vec::with_capacity(QUEUE_SIZE);
             for i in 0..QUEUE_SIZE{
-                msg_avail.push(RecvMessage::new());
+                msg_avail.push(RecvMessage::new(&config));
             }
             unsafe {
 
@@ -233,7 +247,7 @@ fn main(){
                     let mut count = 0;
                     loop {
                         if let Some(avail) = msg_avail.pop() {
-                            let retval = recvmmsg(socket_clone.as_raw_fd(), avail.msgs, VLEN, 0, std::ptr::null_mut());
+                            let retval = recvmmsg(socket_clone.as_raw_fd(), avail.msgs, vlen as c_uint, 0, std::ptr::null_mut());
                             if retval == -1 {
                                 panic!("recvmmsg()");
                             }
@@ -267,10 +281,14 @@ fn main(){
         let socket_clone = Arc::clone(&socket_wrapped);
         move || {
 
-            let mut msgg = RecvMessage::new();
+            let mut msgg = RecvMessage::new(&config);
 
             let mut first:bool = false;
             let mut count = 0;
+            let mut misbehaviours = 0;
+            /*payloads refused because the pipeline of the broker was full, they are submitted
+            again once the broker sends proofs of inclusion, a sign that its batches move on */
+            let mut deferred: Vec<Payload> = Vec::new();
 
             let ret = core_affinity::set_for_current(CoreId { id: 2});
             if ret {
@@ -283,20 +301,78 @@ fn main(){
                                 first = true;
                             }
                             let now: SystemTime = SystemTime::now();
-                            let mut vec_sigs: Vec<(Signature,u64)> = Vec::with_capacity(retval as usize);
+                            let mut vec_sigs: Vec<(RootSignature,u64)> = Vec::with_capacity(retval as usize);
+                            let mut resubmissions: Vec<Vec<u8>> = Vec::new();
                             unsafe {
-                                let bufs_slice = slice::from_raw_parts(msg.bufs as *mut [u8;BUFSIZE as usize], retval as usize);
                                 for i in 0..retval as usize {
-                                    let (p,client) = MerklePath::from_bytes(&bufs_slice[i]);
-                                    let sig = signed_fake_root[client as usize];
-                                    vec_sigs.push((sig,client));
+                                    /*the broker restarted and lost the batch our payload was in */
+                                    match Resubmit::from_bytes(msg.get_buf(i), &broker_pk) {
+                                        Ok(resubmit) => {
+                                            if let Some(payload) = pending.resubmission(&resubmit) {
+                                                let sk = &sk_clone[payload.num_id as usize];
+                                                resubmissions.push(Submission::sign(payload, sk, BROKER_ID).to_bytes());
+                                            }
+                                            continue;
+                                        },
+                                        Err(e @ NoticeError::InvalidSignature) => {
+                                            eprintln!("dropped a message from the broker: {e}");
+                                            continue;
+                                        },
+                                        Err(NoticeError::NotANotice) => (),
+                                    }
+                                    /*the broker had already delivered a payload with this sequence number,
+                                    still holds a previous payload of the client in a batch, or has no room */
+                                    match Rejection::from_bytes(msg.get_buf(i), &broker_pk) {
+                                        Ok(rejection) => {
+                                            match rejection.reason {
+                                                RejectionReason::Stale { .. } => eprintln!("client {} submitted stale sequence number {}",rejection.client_id,rejection.seq_num),
+                                                RejectionReason::InFlight { batch_id } => eprintln!("client {} submitted sequence number {} while batch {} holds its previous payload",rejection.client_id,rejection.seq_num,batch_id),
+                                                RejectionReason::PipelineFull => {
+                                                    let resubmit = Resubmit { client_id: rejection.client_id, seq_num: rejection.seq_num };
+                                                    deferred.extend(pending.resubmission(&resubmit));
+                                                },
+                                            }
+                                            if let Err(e) = pending.resync(&rejection) {
+                                                misbehaviours += 1;
+                                                eprintln!("broker misbehaviour ({misbehaviours} so far): {e}");
+                                            }
+                                            continue;
+                                        },
+                                        Err(e @ NoticeError::InvalidSignature) => {
+                                            eprintln!("dropped a message from the broker: {e}");
+                                            continue;
+                                        },
+                                        Err(NoticeError::NotANotice) => (),
+                                    }
+                                    let (p,context,client) = match MerklePath::from_bytes(msg.get_buf(i)) {
+                                        Ok(decoded) => decoded,
+                                        Err(e) => {
+                                            eprintln!("dropped a message from the broker: {e}");
+                                            continue;
+                                        },
+                                    };
+                                    if let Err(e) = pending.check_inclusion(client, &p, &context) {
+                                        misbehaviours += 1;
+                                        eprintln!("broker misbehaviour ({misbehaviours} so far): {e}");
+                                        continue;
+                                    }
+                                    /*the client signs for the position its proof of inclusion is for */
+                                    let signature = sk_clone[client as usize].sign(&context.to_bytes(), DST, &[]);
+                                    vec_sigs.push((RootSignature { pos: p.get_index(), signature },client));
                                 }
                                 eprintln!("elapsed to get sigz {:?}",now.elapsed().unwrap());
+                                if !vec_sigs.is_empty() {
+                                    resubmissions.extend(deferred.drain(..).map(|payload| {
+                                        let sk = &sk_clone[payload.num_id as usize];
+                                        Submission::sign(payload, sk, BROKER_ID).to_bytes()
+                                    }));
+                                }
                                 
 
                                 let payloads: Vec<Vec<u8>> = vec_sigs.iter()
-                                .map(|x| Payload::new(x.1, 0, Vec::from(x.0.serialize())))
+                                .map(|x| x.0.to_payload(x.1))
                                 .map(|x| x.to_bytes())
+                                .chain(resubmissions)
                                 .collect();
                             
                                 // msgg.fill_to_send(addr, &payloads);
@@ -336,8 +412,8 @@ fn main(){
                                     let mut b = 0;
                                     while b < allowance {
                                         let mut len = allowance - b;
-                                        if len > VLEN as usize {
-                                            len = VLEN as usize;
+                                        if len > vlen {
+                                            len = vlen;
                                         }
                                         
                                         if len < payload_slice.len() {
@@ -354,7 +430,7 @@ fn main(){
                                             panic!("sendmmsg");
                                         }
                                         
-                                        b += VLEN as usize; 
+                                        b += vlen; 
                                         sent += len;        
                                     }
                                 }
@@ -0,0 +1,107 @@
+use std::io::BufReader;
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::str::FromStr;
+use std::{env, process};
+
+use rainfall::batch::{BatchId, Payload};
+use rainfall::batch_log::BatchLogReader;
+use rainfall::downstream::{DeliveryError, Downstream};
+use rainfall::registry::ClientRegistry;
+use rainfall::signing::BrokerId;
+
+/*id of the broker whose batches are accepted */
+const BROKER_ID: BrokerId = 0;
+
+
+/*The application the payloads are delivered to. It only keeps track of how much it received */
+struct Application {
+    payloads: usize,
+    bytes: usize,
+}
+
+impl Application {
+    fn deliver(&mut self, _batch_id: BatchId, payload: &Payload) {
+        self.payloads += 1;
+        self.bytes += payload.message.len();
+    }
+}
+
+
+fn main(){
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let listen_addr;
+    /*a copy of the registry the broker keeps up to date, which holds the keys the signatures are checked against */
+    let registry_path;
+
+    match args.len() {
+        2 => {
+            let delim:Vec<&str> = args[0].split(":").collect();
+            assert!(delim.len() == 2);
+            let addr: Vec<u8> = delim[0].split(".").map(|x| FromStr::from_str(x).unwrap()).collect();
+            assert!(addr.len() == 4);
+            let port: u16 = FromStr::from_str(delim[1]).unwrap();
+            listen_addr = SocketAddrV4::new(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]), port);
+            registry_path = args[1].as_str();
+        },
+        _ => {
+            println!("Not the right number of arguments");
+            println!("Format is: x.x.x.x:port_number path/to/registry");
+            process::exit(1);
+        }
+    }
+
+    let registry = ClientRegistry::load(registry_path).expect("failed to load the registry");
+    println!("{} clients registered",registry.len());
+
+    let mut application = Application { payloads: 0, bytes: 0 };
+    let mut downstream = Downstream::new(BROKER_ID, registry, |batch_id, payload: &Payload| application.deliver(batch_id, payload));
+
+    let listener = TcpListener::bind(listen_addr).expect("couldn't bind to address");
+    println!("waiting for the broker on {}",listen_addr);
+
+    /*one broker at a time: once its connection is closed, the next one is accepted */
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("connection failed: {}",e);
+                continue;
+            }
+        };
+        let batches = match BatchLogReader::from_reader(BufReader::new(stream)) {
+            Ok(batches) => batches,
+            Err(e) => {
+                eprintln!("not a broker: {}",e);
+                continue;
+            }
+        };
+
+        for batch in batches {
+            let batch = match batch {
+                Ok(batch) => batch,
+                Err(e) => {
+                    eprintln!("connection to the broker lost: {}",e);
+                    break;
+                }
+            };
+
+            /*a client unknown to the server may have signed up since the registry was loaded */
+            let mut result = downstream.receive(&batch);
+            if let Err(DeliveryError::UnknownClient { .. }) = result {
+                match ClientRegistry::load(registry_path) {
+                    Ok(registry) => {
+                        downstream.set_registry(registry);
+                        result = downstream.receive(&batch);
+                    },
+                    Err(e) => eprintln!("failed to reload the registry: {}",e),
+                }
+            }
+
+            match result {
+                Ok(delivered) => println!("Batch {} delivered: {} payloads ({} batches so far)",batch.context.batch_id,delivered,downstream.delivered()),
+                Err(e) => eprintln!("batch refused: {}",e),
+            }
+        }
+    }
+}
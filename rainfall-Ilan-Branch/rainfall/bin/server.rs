@@ -1,9 +1,9 @@
 use core::slice;
 use std::fmt::Debug;
 use std::fs::File;
-use std::sync::mpsc::TryRecvError;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender};
 use std::{env, path, process};
-use blst::min_pk::{PublicKey, Signature};
+use blst::min_pk::{PublicKey, SecretKey, Signature};
 use rainfall::merkle::MerklePath;
 use std::net::{Ipv4Addr,SocketAddrV4,UdpSocket};
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -19,30 +19,39 @@ use std::os::fd::AsRawFd;
 
 use std::str::FromStr;
 
-use rainfall::batch::{self, BatchConstruction, BatchManager, BatchProposal, BatchType, DistilledBatch, Payload};
+use rainfall::batch::{BatchError, DistilledBatch, Payload, RootSignature, SealedBatch, Submission};
+use rainfall::client_state::ClientState;
+use rainfall::batch_log::{BatchLog, LoggedBatch};
+use rainfall::config::BatchConfig;
+use rainfall::downstream::BatchSender;
+use rainfall::recovery::recover;
 use rainfall::recvmessage::RecvMessage;
+use rainfall::registry::{ClientRegistry, RegistryError};
+use rainfall::rejection::Rejection;
+use rainfall::signing::BrokerId;
+use rainfall::signup::{SignUp, SignUpReply};
+use rainfall::wal::{BatchWal, WalRecord};
 
 /* Networking part */
 const QUEUE_SIZE: usize = 100;
-const VLEN:c_uint = 1024;
-const BUFSIZE: c_uint = 1024;
-const TIMEOUT_DURATION:u64 = 1;
-const BATCH_SIZE:u64 =1<<16;
-
-
-
-#[derive(Clone, Copy)]
-enum ClientState {
-    NotAssignedToBatch,
-    AssignedToBatch(usize,usize),
-    WaitingForSignature(u8),
-}
-
-
-fn get_pks_from_file() -> Vec<PublicKey>{
-    let mut pks: Vec<PublicKey> = Vec::with_capacity(2 * BATCH_SIZE as usize);
+/*how often (in ms) the worker checks the deadlines of the batches when no packet arrives */
+const TICK_DURATION: u64 = 10;
+const BROKER_ID: BrokerId = 0;
+const REGISTRY_PATH: &str = "src/keys/registry";
+/*the key the broker signs its notices to the clients with, the clients hold its public key */
+const BROKER_SK_PATH: &str = "src/keys/broker_sk";
+const BATCH_LOG_PATH: &str = "batches.log";
+const WAL_PATH: &str = "batches.wal";
+
+
+/*The keys of src/keys/pks come without proofs of possession: they are trusted because the
+operator generated them together with the secret keys of src/keys/sks, so none
+of them can be a rogue key chosen to cancel the others in an aggregate. Any other client
+has to sign up with a proof of possession (see SignUp). */
+fn get_pks_from_file(num_keys: usize) -> Vec<PublicKey>{
+    let mut pks: Vec<PublicKey> = Vec::with_capacity(num_keys);
     let mut f = File::open("src/keys/pks").expect("Unable to open file");
-    for i in 0..(2* BATCH_SIZE) {
+    for i in 0..num_keys {
         let mut buf = [0u8;48];
         f.read(&mut buf).expect("failed to read");
         match PublicKey::from_bytes(&buf) {
@@ -53,19 +62,201 @@ fn get_pks_from_file() -> Vec<PublicKey>{
     pks
 }
 
+fn get_broker_sk() -> SecretKey {
+    let mut f = File::open(BROKER_SK_PATH).expect("Unable to open file");
+    let mut buf = [0u8;32];
+    f.read_exact(&mut buf).expect("failed to read");
+    SecretKey::from_bytes(&buf).expect("invalid broker secret key")
+}
+
+
+/*The registry is loaded from its file. The first time, it is built from the 
+positional key file (the id of a client is the index of its key) and saved */
+fn load_registry(num_keys: usize) -> ClientRegistry {
+    match ClientRegistry::load(REGISTRY_PATH) {
+        Ok(registry) => registry,
+        Err(e) => {
+            println!("could not load {} ({}), importing src/keys/pks",REGISTRY_PATH,e);
+            let registry = ClientRegistry::from_pks(get_pks_from_file(num_keys));
+            if let Err(e) = registry.save(REGISTRY_PATH) {
+                handle_error(e);
+            }
+            registry
+        }
+    }
+}
+
 
 fn handle_error<E>(e: E) where E: Debug {
     println!("error handler: {:?}",e);
 }
 
 
+/*Errors from the batch manager come from packets that are late, duplicated or malformed.
+They are logged and counted, and the packet is dropped */
+fn handle_batch_error(e: BatchError, batch_errors: &mut usize) {
+    *batch_errors += 1;
+    eprintln!("batch error #{}: {}",batch_errors,e);
+}
+
+
+/*Once a batch is distilled it is written to the batch log, handed to the downstream thread and
+forgotten by the batch manager and the write-ahead log, and its clients are free to submit their
+next payload. A batch that could not be logged keeps its records in the write-ahead log */
+fn report_distilled(distilled: &DistilledBatch, registry: &mut ClientRegistry, batch_log: &mut BatchLog, wal: &mut BatchWal, tx_downstream: &Option<Sender<LoggedBatch>>) {
+    let batch_id = distilled.get_batch_id();
+    match batch_log.append(distilled) {
+        Ok(()) => if let Err(e) = wal.delivered(batch_id) {
+            eprintln!("write-ahead log could not be compacted: {}",e);
+        },
+        Err(e) => eprintln!("batch {} could not be logged: {}",batch_id,e),
+    }
+    if let Some(tx_downstream) = tx_downstream {
+        if tx_downstream.send(LoggedBatch::from_distilled(distilled)).is_err() {
+            eprintln!("batch {} not sent: no downstream server left",batch_id);
+        }
+    }
+    let signers = distilled.bitmap.iter().filter(|b| **b).count();
+    registry.record_delivered(&distilled.delivered_payloads());
+    let released = registry.release(batch_id);
+    println!("Batch {} distilled: {} signatures, {} exceptions, {} clients released",batch_id,signers,distilled.exceptions.len(),released);
+}
+
+
+/*Returns a message the worker can fill. If every message is in use, 
+waits for the sender thread to give one back */
+fn take_msg(msg_avails: &mut Vec<RecvMessage>, rx_worker_s: &Receiver<RecvMessage>) -> RecvMessage {
+    while let Ok(msg) = rx_worker_s.try_recv() {
+        msg_avails.push(msg);
+    }
+
+    match msg_avails.pop() {
+        Some(msg) => msg,
+        None => rx_worker_s.recv().expect("sender thread is gone"),
+    }
+}
+
+
+/*Registers the key of a new client and sends it back its numerical id.
+Its entry is appended to the registry file right away so that the client is still known after a restart */
+fn handle_sign_up(signup: &SignUp, addr: sockaddr_in, registry: &mut ClientRegistry, msg_avails: &mut Vec<RecvMessage>, tx_sender: &SyncSender<(RecvMessage,usize)>, rx_worker_s: &Receiver<RecvMessage>) {
+    let known = registry.len();
+    let client_id = match registry.sign_up(signup) {
+        Ok(client_id) => client_id,
+        Err(e) => {
+            eprintln!("sign-up refused: {}",e);
+            return;
+        }
+    };
+
+    if registry.len() > known {
+        println!("client {} signed up",client_id);
+        if let Err(e) = registry.append_client(REGISTRY_PATH, client_id) {
+            handle_error(e);
+        }
+    }
+
+    let reply = SignUpReply { client_id, pk: signup.pk };
+    let mut msg = take_msg(msg_avails, rx_worker_s);
+    msg.fill_to_send(addr, &[reply.to_bytes()]);
+    if let Err(e) = tx_sender.send((msg,1)) {
+        handle_error(e);
+    }
+}
+
+
+/*Sends a single message to a client, e.g. to tell it its submission was dropped 
+because its sequence number was already delivered */
+fn send_notice(notice: Vec<u8>, addr: sockaddr_in, msg_avails: &mut Vec<RecvMessage>, tx_sender: &SyncSender<(RecvMessage,usize)>, rx_worker_s: &Receiver<RecvMessage>) {
+    let mut msg = take_msg(msg_avails, rx_worker_s);
+    msg.fill_to_send(addr, &[notice]);
+    if let Err(e) = tx_sender.send((msg,1)) {
+        handle_error(e);
+    }
+}
+
+
+/*A submission that cannot be parsed or that is signed by someone else than its client is dropped */
+fn authenticated_submission(buf: &[u8], registry: &ClientRegistry, unauthenticated: &mut usize) -> Option<(Submission,PublicKey)> {
+    let submission = match Submission::from_bytes(buf) {
+        Ok(submission) => submission,
+        Err(e) => {
+            handle_error(e);
+            return None;
+        }
+    };
+    match registry.authenticate(BROKER_ID, &submission) {
+        Ok(pk) => Some((submission,pk)),
+        Err(e) => {
+            *unauthenticated += 1;
+            eprintln!("dropping submission #{}: {}",unauthenticated,e);
+            None
+        }
+    }
+}
+
+/*A submission that is not fresh is answered with the reason it was dropped,
+signed so that the client can't be made to drop payloads by anyone else */
+fn reject(e: &RegistryError, sk: &SecretKey, addr: sockaddr_in, msg_avails: &mut Vec<RecvMessage>, tx_sender: &SyncSender<(RecvMessage,usize)>, rx_worker_s: &Receiver<RecvMessage>) {
+    match Rejection::from_error(e) {
+        Some(rejection) => send_notice(rejection.to_bytes(sk), addr, msg_avails, tx_sender, rx_worker_s),
+        None => handle_error(e),
+    }
+}
+
+
+/*The write-ahead log has to hold a record before the broker acts on it, 
+a failure to write it is reported but does not stop the broker */
+fn write_ahead(wal: &mut BatchWal, record: &WalRecord) {
+    if let Err(e) = wal.append(record) {
+        eprintln!("batch {} could not be written ahead: {}",record.get_batch_id(),e);
+    }
+}
+
+
+/*Sends to every client of a sealed batch its proof of inclusion along with 
+the signing context of the batch, in chunks of at most vlen messages.
+A chunk whose proofs can't be written is reported and not sent */
+fn send_inclusion_proofs(sealed: &SealedBatch, vlen: usize, msg_avails: &mut Vec<RecvMessage>, tx_sender: &SyncSender<(RecvMessage,usize)>, rx_worker_s: &Receiver<RecvMessage>) {
+    assert!(sealed.addrs.len() == sealed.client_ids.len());
+
+    let paths: Vec<MerklePath> = match (0..sealed.client_ids.len()).map(|pos| sealed.merkle.find_merkle_path(pos)).collect() {
+        Ok(paths) => paths,
+        Err(e) => {
+            handle_error(e);
+            return;
+        }
+    };
+
+    let chunks = paths.chunks(vlen)
+        .zip(sealed.addrs.chunks(vlen))
+        .zip(sealed.client_ids.chunks(vlen));
+
+    for ((head_path,head_addr),head_clients) in chunks {
+        let mut msg = take_msg(msg_avails, rx_worker_s);
+        if let Err(e) = msg.fill(head_addr, head_path, &sealed.context, head_clients) {
+            handle_error(e);
+            msg_avails.push(msg);
+            continue;
+        }
+
+        match tx_sender.send((msg,head_path.len())) {
+            Ok(_) => (),
+            Err(e) => handle_error(e),
+        }
+    }
+}
+
+
 fn main(){
 
     let args: Vec<String>= env::args().skip(1).collect();
     let server_addr;
+    let config = BatchConfig::default();
 
+    /*the addresses after the one of the broker are the downstream servers the distilled batches are sent to */
     match args.len() {
-        1 => { 
+        n if n >= 1 => { 
             let delim:Vec<&str> = args[0].split(":").collect();
             assert!(delim.len() == 2);
             let addr: Vec<u8> = delim[0].split(".").map(|x| FromStr::from_str(x).unwrap()).collect();
@@ -75,7 +266,7 @@ fn main(){
         },
         _ => {
             println!("Not the right number of arguments");
-            println!("Format is: x.x.x.x:port_number");
+            println!("Format is: x.x.x.x:port_number [downstream x.x.x.x:port_number ...]");
             process::exit(1);
         }
     }
@@ -126,29 +317,68 @@ fn main(){
     let (tx_sender,rx_sender) = mpsc::sync_channel::<(RecvMessage,usize)>(QUEUE_SIZE);
     let (tx_worker_s, rx_worker_s) = mpsc::sync_channel::<RecvMessage>(QUEUE_SIZE);
 
-    let pks = get_pks_from_file();
-    println!("{}",pks.len());
+    let broker_sk = get_broker_sk();
+    let mut registry = load_registry(2 * config.batch_size);
+    println!("{} clients registered",registry.len());
+
+    /*The proposals that were in flight when the broker stopped are put back from the logs,
+    and the clients of the batches that were still in construction are told to resubmit */
+    let recovery = recover(BROKER_ID, config, &mut registry, WAL_PATH, BATCH_LOG_PATH).expect("failed to recover from the logs");
+    println!("recovered {} proposals, {} clients have to resubmit",recovery.proposals.len(),recovery.resubmits.len());
+    let proposals = recovery.proposals;
+    let resubmits = recovery.resubmits;
+    let mut wal = recovery.wal;
+
+    let mut senders: Vec<BatchSender> = args[1..].iter()
+        .filter_map(|addr| match BatchSender::connect(addr.as_str()) {
+            Ok(sender) => Some(sender),
+            Err(e) => {
+                eprintln!("could not connect to downstream server {}: {}",addr,e);
+                None
+            }
+        })
+        .collect();
+    println!("sending distilled batches to {} downstream servers",senders.len());
 
-    /*This fixed-size array tracks the batch assignement for each client.
-    Each client is identified by their numerical ID, which is the index of their 
-    public key in the vector of pks.
-    If a client is not yet assigned to a batch, the client is in the WaitingForPayload state.
-    Once a client is assigned to a batch, the client is in the WaitingForSignature state.
-    After successfully sending a batch to the server, the client then goes back to the WaitingForPayload state.
-    */
-    let mut batch_per_id = Arc::new(Mutex::new(vec![ClientState::NotAssignedToBatch;150000 as usize]));
-    let mut list_payload = Arc::new(Mutex::new(Vec::<Payload>::new()));
-    let mut batchmanager = BatchManager::new();
-    
     let mut handles: Vec<JoinHandle<()>> = vec![];
-    let timeout_duration = Duration::from_secs(TIMEOUT_DURATION);
+
+    /*The distilled batches are sent to the downstream servers by their own thread, so that a slow
+    server does not hold up the worker. A downstream server whose connection fails is dropped */
+    let tx_downstream = if senders.is_empty() {
+        None
+    } else {
+        let (tx_downstream,rx_downstream) = mpsc::channel::<LoggedBatch>();
+        handles.push(thread::spawn(move || {
+            for logged in rx_downstream {
+                senders.retain_mut(|sender| match sender.send(&logged) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!("dropping a downstream server: {}",e);
+                        false
+                    }
+                });
+                if senders.is_empty() {
+                    break;
+                }
+            }
+        }));
+        Some(tx_downstream)
+    };
+
+    /*The registry holds the public key and the batch assignement of each client.
+    Each client is identified by their numerical ID, and packets from unregistered ids are dropped.
+    A client is assigned to the batch in construction when its payload arrives, waits for 
+    its signature once the batch is sealed, and is released once the batch is distilled.
+    */
+    let batch_per_id = Arc::new(Mutex::new(registry));
+    let mut batchmanager = recovery.manager;
     
     let receiver_thread = thread::spawn({
         let socket_clone = Arc::clone(&socket_wrapped);
         move || {
             let mut msg_avail: Vec<RecvMessage>= Vec::with_capacity(QUEUE_SIZE);
-            for i in 0..QUEUE_SIZE{
-                msg_avail.push(RecvMessage::new());
+            for _ in 0..QUEUE_SIZE{
+                msg_avail.push(RecvMessage::new(&config));
             }
             
             let ret = core_affinity::set_for_current(CoreId { id: 3});
@@ -156,20 +386,16 @@ fn main(){
                 
                 unsafe {
                     println!("PID of receiver thread: {}",gettid());
-                    let mut count: i32 = 0;
                     loop {
                         if exec.load(Ordering::Relaxed){
                             break;
                         }
 
                         if let Some(avail) = msg_avail.pop() {
-                            let retval = recvmmsg(socket_clone.as_raw_fd(), avail.msgs, VLEN, 0, std::ptr::null_mut());
+                            let retval = recvmmsg(socket_clone.as_raw_fd(), avail.msgs, config.vlen as c_uint, 0, std::ptr::null_mut());
                             if retval == -1 {
                                 panic!("recvmmsg()");
                             }
-                            
-                            // count += retval;
-                            // println!("received {}",count);
 
 
                             match tx_worker_r.send((avail,retval)) {
@@ -202,12 +428,20 @@ fn main(){
                 unsafe {
                     
                     let mut msg_avails: Vec<RecvMessage> = Vec::with_capacity(QUEUE_SIZE);
-                    for i in 0..QUEUE_SIZE{
-                        msg_avails.push(RecvMessage::new());
+                    for _ in 0..QUEUE_SIZE{
+                        msg_avails.push(RecvMessage::new(&config));
                     }
                     
-                    let mut count: i32 = 0;
-                    let mut total_received = 0;
+                    let mut batch_errors: usize = 0;
+                    let mut unauthenticated: usize = 0;
+                    let mut batch_log = BatchLog::open(BATCH_LOG_PATH).expect("failed to open the batch log");
+
+                    for sealed in &proposals {
+                        send_inclusion_proofs(sealed, config.vlen, &mut msg_avails, &tx_sender, &rx_worker_s);
+                    }
+                    for (addr,resubmit) in &resubmits {
+                        send_notice(resubmit.to_bytes(&broker_sk), *addr, &mut msg_avails, &tx_sender, &rx_worker_s);
+                    }
                     loop {
     
                         if batchmanager.batches.is_empty() {
@@ -215,107 +449,148 @@ fn main(){
                         }
 
     
-                        match rx_worker_r.recv() {
+                        match rx_worker_r.recv_timeout(Duration::from_millis(TICK_DURATION)) {
                             Ok((msg,num)) => {
                                 /* */
-                                let bufs_slice = slice::from_raw_parts(msg.bufs as *mut [u8;BUFSIZE as usize], VLEN as usize);
-                                let addrs_slice = slice::from_raw_parts(msg.addrs as *mut sockaddr_in, VLEN as usize);
                                 for i in 0..num as usize{
-                                    let decoded_payload = Payload::from_bytes(&bufs_slice[i]);
+                                    if let Ok(signup) = SignUp::from_bytes(msg.get_buf(i)) {
+                                        handle_sign_up(&signup, msg.get_addr(i), &mut batches_clone.lock().unwrap(), &mut msg_avails, &tx_sender, &rx_worker_s);
+                                        continue;
+                                    }
+
+                                    let decoded_payload = Payload::from_bytes(msg.get_buf(i));
                                     match decoded_payload {
                                         Ok(payload) => {
                                             let mut batch_per_id_locked = batches_clone.lock().unwrap();
                                             let client_id = payload.num_id;
-                                            match batch_per_id_locked[client_id as usize] {
+                                            let (state,pk) = match (batch_per_id_locked.get_state(client_id),batch_per_id_locked.get_pk(client_id)) {
+                                                (Ok(state),Ok(pk)) => (state,pk),
+                                                (Err(e),_) | (_,Err(e)) => {
+                                                    eprintln!("dropping packet: {}",e);
+                                                    continue;
+                                                }
+                                            };
+                                            match state {
                                                 ClientState::NotAssignedToBatch => {
-                                                    let (batch_id,pos, addres_vec) = batchmanager.add_to_construction(addrs_slice[i], client_id, payload);
-                                                    batch_per_id_locked[client_id as usize] = ClientState::AssignedToBatch(batch_id,pos);
-                                                    if let Some((addrs,tree,clients)) = addres_vec {
-                                                        println!("New batch was created, we should send the proofs of inclusions to the clients, client id {}",client_id);
-                                                        
-                                                        let mut paths: Vec<MerklePath> = Vec::new();
-                                                        for i in &clients {
-                                                            if let ClientState::AssignedToBatch(batch,pos) = batch_per_id_locked[*i as usize]{
-                                                                paths.push(tree.find_merkle_path(pos));
+                                                    /*a new payload comes with the individual signature of its client */
+                                                    let (submission,pk) = match authenticated_submission(msg.get_buf(i), &batch_per_id_locked, &mut unauthenticated) {
+                                                        Some(authenticated) => authenticated,
+                                                        None => continue,
+                                                    };
+                                                    /*a payload that was already delivered is a replay */
+                                                    if let Err(e) = batch_per_id_locked.check_fresh(&submission.payload) {
+                                                        reject(&e, &broker_sk, msg.get_addr(i), &mut msg_avails, &tx_sender, &rx_worker_s);
+                                                        continue;
+                                                    }
+                                                    /*the assignment is written ahead before the batch manager makes it.
+                                                    A client that finds the pipeline full is told to submit again later */
+                                                    let (batch_id,pos) = match batchmanager.next_slot() {
+                                                        Ok(slot) => slot,
+                                                        Err(e) => {
+                                                            handle_batch_error(e, &mut batch_errors);
+                                                            if let Some(rejection) = Rejection::from_batch_error(client_id, submission.payload.seq_num, &e) {
+                                                                send_notice(rejection.to_bytes(&broker_sk), msg.get_addr(i), &mut msg_avails, &tx_sender, &rx_worker_s);
                                                             }
+                                                            continue;
                                                         }
-                                                        
-                                                        let mut slice_path = &paths[..];
-                                                        let mut slice_addrs = &addrs[..];
-                                                        let mut slice_clients = &clients[..];
-
-                                                        assert!(slice_path.len() == slice_addrs.len() && slice_clients.len() == slice_path.len());
-                                                        while slice_path.len() > VLEN as usize {
-                                                            let (head_addr,tail_addr) = slice_addrs.split_at(VLEN as usize);
-                                                            let (head_path,tail_path) = slice_path.split_at(VLEN as usize);
-                                                            let (head_clients, tail_clients) = slice_clients.split_at(VLEN as usize);
-                                                            slice_path = tail_path;
-                                                            slice_addrs = tail_addr;
-                                                            slice_clients = tail_clients;
-                                                            let mut msg: RecvMessage = msg_avails.pop().unwrap();
-                                                            msg.fill(head_addr, head_path,head_clients);
-    
-                                                            match tx_sender.send((msg,VLEN as usize)) {
-                                                                Ok(_) => (),
-                                                                Err(e) => handle_error(e),
-                                                            }
-    
-                                                            match rx_worker_s.try_recv() {
-                                                                Ok(msg) => msg_avails.push(msg),
-                                                                Err(e) => {
-                                                                    if e != TryRecvError::Empty {
-                                                                       ();
-                                                                    }
-                                                                }
-                                                            }
+                                                    };
+                                                    write_ahead(&mut wal, &WalRecord::Assigned { batch_id, pos, client_id, addr: msg.get_addr(i), submission: Box::new(submission.clone()) });
+                                                    let addres_vec = match batchmanager.add_to_construction(msg.get_addr(i), client_id, submission, pk) {
+                                                        Ok((added_id,added_pos,addres_vec)) => {
+                                                            debug_assert!((added_id,added_pos) == (batch_id,pos));
+                                                            addres_vec
+                                                        },
+                                                        Err(e) => {
+                                                            handle_batch_error(e, &mut batch_errors);
+                                                            continue;
                                                         }
-                                                        
-                                                        if slice_path.len() != 0 {
-                                                            let mut msg: RecvMessage = msg_avails.pop().unwrap();
-                                                            msg.fill(slice_addrs, slice_path,slice_clients);
-                                                            match tx_sender.send((msg,slice_path.len())) {
-                                                                Ok(_) => (),
-                                                                Err(e) => (),
-                                                            }
+                                                    };
+                                                    if let Err(e) = batch_per_id_locked.assign(client_id, batch_id, pos) {
+                                                        handle_error(e);
+                                                    }
+                                                    if let Some(sealed) = addres_vec {
+                                                        println!("New batch was created, we should send the proofs of inclusions to the clients, client id {}",client_id);
+                                                        write_ahead(&mut wal, &WalRecord::Sealed { batch_id: sealed.context.batch_id });
+                                                        batch_per_id_locked.wait_for_signature(&sealed);
+                                                        send_inclusion_proofs(&sealed, config.vlen, &mut msg_avails, &tx_sender, &rx_worker_s);
+                                                        if let Err(e) = batchmanager.add_start_time(sealed.context.batch_id) {
+                                                            handle_batch_error(e, &mut batch_errors);
                                                         }
-
-                                                        batchmanager.add_start_time(batch_id-1);
                                                     }
                                                 },      
-                                                ClientState::AssignedToBatch(batch_id,pos) => {
-
-                                                    total_received+=1;
-                                                    println!("total received: {}", total_received);
-                                                    let pk = pks[client_id as usize];
-                                                    let sig = Signature::deserialize(&payload.message)
-                                                        .expect("failed to get signature from bytes");
-                                                    batchmanager.add_to_proposal(batch_id,pos,client_id as usize,sig,pk,&mut count);
+                                                /*the client already has a payload in the batch in construction */
+                                                ClientState::AssignedToBatch(_,_) => {
+                                                    /*the client already has a payload in a batch under construction,
+                                                    a second one is rejected until that one is delivered */
+                                                    if let Some((submission,_)) = authenticated_submission(msg.get_buf(i), &batch_per_id_locked, &mut unauthenticated) {
+                                                        if let Err(e) = batch_per_id_locked.check_fresh(&submission.payload) {
+                                                            reject(&e, &broker_sk, msg.get_addr(i), &mut msg_avails, &tx_sender, &rx_worker_s);
+                                                        }
+                                                    }
+                                                },
+                                                ClientState::WaitingForSignature(batch_id,pos) => {
+
+                                                    let root_sig = match RootSignature::from_payload(&payload) {
+                                                        Ok(root_sig) => root_sig,
+                                                        Err(e) => {
+                                                            handle_error(e);
+                                                            continue;
+                                                        }
+                                                    };
+                                                    /*the client signs for the slot its proof of inclusion is for, which has to be its own */
+                                                    if root_sig.pos != pos {
+                                                        eprintln!("client {} signed for position {} of batch {} but is at position {}",client_id,root_sig.pos,batch_id,pos);
+                                                        continue;
+                                                    }
+                                                    match batchmanager.add_to_proposal(batch_id,pos,root_sig.signature,pk) {
+                                                        Ok(_) => (),
+                                                        Err(e) => handle_batch_error(e, &mut batch_errors),
+                                                    }
                                                     
                                                 },
-                                                ClientState::WaitingForSignature(batch_id) => (),
                                             }
                                         },
                                         Err(e) => handle_error(e),
                                     }
     
-                                    match rx_worker_s.try_recv() {
-                                        Ok(msg) => msg_avails.push(msg),
-                                        Err(e) => {
-                                            if e != TryRecvError::Empty {
-                                                ()
-                                            }
-                                        }
+                                    if let Ok(msg) = rx_worker_s.try_recv() {
+                                        msg_avails.push(msg);
                                     }
                                 }
     
+                                /*the assignments of the packets are on disk before the next packets are handled */
+                                if let Err(e) = wal.flush() {
+                                    handle_error(e);
+                                }
+
                                 /*after will need to send a timeout or what not */
                                 match tx_receiver.send(msg){
                                     Ok(_) => (),
                                     Err(e) => handle_error(e),
                                 }
                             },
+                            Err(RecvTimeoutError::Timeout) => (),
                             Err(e) => handle_error(e),
                         }
+
+                        /*a partial batch is sealed once its seal timeout expires, even if no packet arrives */
+                        for sealed in batchmanager.poll_seal(SystemTime::now()) {
+                            println!("Batch {} sealed by timeout with {} clients",sealed.context.batch_id,sealed.client_ids.len());
+                            write_ahead(&mut wal, &WalRecord::Sealed { batch_id: sealed.context.batch_id });
+                            batches_clone.lock().unwrap().wait_for_signature(&sealed);
+                            send_inclusion_proofs(&sealed, config.vlen, &mut msg_avails, &tx_sender, &rx_worker_s);
+                            if let Err(e) = batchmanager.add_start_time(sealed.context.batch_id) {
+                                handle_batch_error(e, &mut batch_errors);
+                            }
+                        }
+
+                        /*proposals whose signature timeout expired are distilled, whether or not signatures keep coming */
+                        batchmanager.poll_expired(SystemTime::now());
+
+                        /*every batch distilled since the last tick, on timeout or on quorum, is handed off */
+                        for distilled in batchmanager.drain_distilled() {
+                            report_distilled(&distilled, &mut batches_clone.lock().unwrap(), &mut batch_log, &mut wal, &tx_downstream);
+                        }
                     }
                 }
             }
@@ -331,7 +606,6 @@ fn main(){
             if ret {
                 unsafe {
                     println!("PID of sender thread: {}",gettid());
-                    let mut sent = 0;
                     loop {
                         match rx_sender.recv() {
                             Ok((msg,num)) => {
@@ -340,9 +614,6 @@ fn main(){
                                         panic!("sendmmsg");
                                     }
 
-                                    // sent += send_retval;
-                                    // println!("sent {}",sent);
-
                                     tx_worker_s.send(msg).unwrap();
                             },
                             
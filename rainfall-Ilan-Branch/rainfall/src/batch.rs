@@ -1,20 +1,19 @@
-use std::{fmt, mem, vec};
-use crate::merkle::MerkleTree;
+use std::{collections::BTreeMap, fmt, vec};
+use crate::config::BatchConfig;
+use crate::merkle::{MerkleBuilder, MerkleTree};
 use crate::signature_tree::SignatureTree;
-use blst::{min_pk::{AggregateSignature, PublicKey, Signature}, BLST_ERROR};
+use crate::signing::{payload_message, verify_payload, BrokerId, SigningContext, DST};
+use blake3::Hash;
+use blst::{min_pk::{AggregateSignature, PublicKey, SecretKey, Signature}, BLST_ERROR};
 use serde::{Serialize,Deserialize};
 use libc::*;
 use std::time::{SystemTime,Duration};
 
-type SequenceNumber = u64;
-type NumericalIdentifier = u64;
-type BatchId = usize;
-type PositionInBatch = usize;
+pub type SequenceNumber = u64;
+pub type NumericalIdentifier = u64;
+pub type BatchId = usize;
+pub type PositionInBatch = usize;
 
-const BATCH_SIZE:u64 = 1<<16 ;
-const TIMEOUT_DURATION_BATCH: u64= 500;
-const FAKE_ROOT: [u8;32] = [200, 117, 111, 57, 59, 197, 34, 95, 163, 98, 125, 151, 19, 45, 52, 158, 129, 137, 
-                            95, 68, 115, 72, 118, 235, 175, 93, 230, 204, 31, 175, 122, 223];
 
 
 #[derive(Serialize,Deserialize,Clone,Debug,PartialEq, Eq,Hash)]
@@ -24,6 +23,24 @@ pub struct Payload {
     pub message: Vec<u8>,
 }
 
+/// A payload along with the signature of its client over it (see `payload_message`).
+/// This is what clients send to the broker. The individual signature is what still
+/// authenticates the payload if the client misses the signature timeout of its batch.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Submission {
+    pub payload: Payload,
+    pub signature: Signature,
+}
+
+/// What a client sends back once it checked its proof of inclusion: its signature of the
+/// signing context of the batch, for the position the proof is for (see `MerklePath::get_index`).
+/// It travels as the message of a payload of the client.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct RootSignature {
+    pub pos: PositionInBatch,
+    pub signature: Signature,
+}
+
 #[derive(Debug)]
 pub struct NotAPayload;
 impl fmt::Display for NotAPayload    {
@@ -32,32 +49,128 @@ impl fmt::Display for NotAPayload    {
     }
 }
 
+/// Everything that can go wrong when the server hands something to the `BatchManager`.
+/// Apart from `InvalidConfig`, which is returned before the manager exists, none of them
+/// is fatal: they come from packets that arrived too late, twice, or that do not match
+/// any batch, and the server is expected to drop the packet.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum BatchError {
+    /// No batch has this id
+    UnknownBatch(BatchId),
+    /// The batch exists but is not in the state the operation needs
+    WrongState(BatchId),
+    /// The position is past the end of the batch
+    PositionOutOfRange { batch_id: BatchId, pos: PositionInBatch },
+    /// The client at this position already signed the batch
+    DuplicateSignature { batch_id: BatchId, pos: PositionInBatch },
+    /// The signature is not the one of the root of the batch by the client at this position
+    InvalidSignature { batch_id: BatchId, pos: PositionInBatch },
+    /// Every batch in construction is full and waits for a proposal to be distilled
+    PipelineFull,
+    /// The batch has no payload, so there is no root to propose
+    EmptyBatch(BatchId),
+    /// The submissions, the keys and the leaves of the Merkle tree of the batch are not as many
+    MismatchedLengths(BatchId),
+    /// The parameter of the `BatchConfig` is out of its range
+    InvalidConfig(&'static str),
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatchError::UnknownBatch(batch_id) => write!(f, "batch {} does not exist", batch_id),
+            BatchError::WrongState(batch_id) => write!(f, "batch {} is not in the expected state", batch_id),
+            BatchError::PositionOutOfRange { batch_id, pos } => write!(f, "position {} is out of range in batch {}", pos, batch_id),
+            BatchError::DuplicateSignature { batch_id, pos } => write!(f, "position {} already signed batch {}", pos, batch_id),
+            BatchError::InvalidSignature { batch_id, pos } => write!(f, "invalid signature for position {} of batch {}", pos, batch_id),
+            BatchError::PipelineFull => write!(f, "every batch in construction is full"),
+            BatchError::EmptyBatch(batch_id) => write!(f, "batch {} has no payload", batch_id),
+            BatchError::MismatchedLengths(batch_id) => write!(f, "batch {} does not have as many keys and leaves as submissions", batch_id),
+            BatchError::InvalidConfig(parameter) => write!(f, "invalid batch configuration: {}", parameter),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct BatchConstruction { 
     batch_id: BatchId,
     addrs: Vec<sockaddr_in>,
     clients_ids : Vec<u64>,
-    payloads : Vec<Payload>,
+    submissions : Vec<Submission>,
+    pks: Vec<PublicKey>,
+    /// The Merkle tree of the payloads added so far, so that sealing the batch does not stall the worker
+    merkle: MerkleBuilder,
     size: usize,
+    opened_at: Option<SystemTime>,
+    batch_size: usize,
+    seal_timeout: Duration,
+}
+
+/// A batch that just went from construction to proposal. It holds everything
+/// the server needs to send the proofs of inclusion to the clients of the batch:
+/// the client at index `i` of `addrs` and `client_ids` is at position `i` in the batch.
+#[derive(Debug)]
+pub struct SealedBatch {
+    pub context: SigningContext,
+    pub addrs: Vec<sockaddr_in>,
+    pub merkle: MerkleTree,
+    pub client_ids: Vec<u64>,
 }
 
 #[derive(Debug)]
 pub struct BatchProposal {
     batch_id : BatchId,
+    broker_id: BrokerId,
     pub merkle: MerkleTree,
     pub bitmap: Vec<bool>,
+    payloads: Vec<Payload>,
+    individual_sigs: Vec<Signature>,
+    pks: Vec<PublicKey>,
     list_sigs: Vec<Signature>,
     lists_pks: Vec<PublicKey>,
-    pos_to_cliendid: Vec<usize>,
+    signed: usize,
     start_time: Option<SystemTime>,
     timeout_duration: Duration,
-    has_timeout: bool,
 }
 
+/// Client of a distilled batch that did not sign its root in time (or whose
+/// signature was invalid). Its payload is still delivered, authenticated by
+/// the individual signature it was submitted with.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Exception {
+    pub position: PositionInBatch,
+    pub signature: Signature,
+}
+
+/// The payloads set in `bitmap` or covered by one of `exceptions`, in the order of the
+/// batch. Positions past the end of `payloads` are ignored.
+pub(crate) fn delivered_payloads<'a>(payloads: &'a [Payload], bitmap: &[bool], exceptions: &[Exception]) -> Vec<&'a Payload> {
+    let mut excepted = vec![false;payloads.len()];
+    for exception in exceptions {
+        if let Some(excepted) = excepted.get_mut(exception.position) {
+            *excepted = true;
+        }
+    }
+
+    payloads.iter()
+        .enumerate()
+        .filter(|(pos,_)| bitmap.get(*pos).copied().unwrap_or(false) || excepted[*pos])
+        .map(|(_,p)| p)
+        .collect()
+}
+
+/// A batch that is done collecting signatures. The payloads of the positions set
+/// in `bitmap` are authenticated by the aggregate signature of `sigtree`, the
+/// other ones by the individual signature of their exception. Payloads in neither
+/// are not authenticated and are not delivered.
 #[derive(Debug)]
 pub struct DistilledBatch {
     batch_id : BatchId,
+    context: SigningContext,
+    payloads: Vec<Payload>,
+    pub bitmap: Vec<bool>,
     pub sigtree: SignatureTree,
+    pub exceptions: Vec<Exception>,
 }
 
 #[derive(Debug)]
@@ -68,36 +181,59 @@ pub enum BatchType {
     DistilledBatch(DistilledBatch),
 }
 
+/// Keeps the batches of the broker from construction to distillation. Distilled batches
+/// stay here until they are handed off with `drain_distilled`, after which they are
+/// forgotten: at any time the manager only holds the batches in construction and the
+/// batches that are not delivered yet.
+/// Several batches go through each phase at the same time (see `BatchConfig`), so that
+/// new payloads keep being batched while older batches collect their signatures.
 #[derive(Debug)]
 pub struct BatchManager {
-    pub batches: Vec<BatchType>,
-    batch_id: BatchId,
+    pub batches: BTreeMap<BatchId,BatchType>,
+    /// Ids of the batches in construction, oldest first
+    open: Vec<BatchId>,
+    /// Index in `open` of the batch the next payload goes to
+    next_open: usize,
+    /// Id of the next batch to open. Ids are never reused, even once their batch is gone.
+    next_batch_id: BatchId,
+    broker_id: BrokerId,
+    config: BatchConfig,
 }
 
 impl BatchConstruction {
-    pub fn new(batch_id: BatchId) -> Self{
+    pub fn new(batch_id: BatchId, config: &BatchConfig) -> Self{
         Self {
             batch_id,
             addrs: Vec::new(),
             clients_ids: Vec::new(),
-            payloads: Vec::new(),
+            submissions: Vec::new(),
+            pks: Vec::new(),
+            merkle: MerkleBuilder::new(),
             size: 0,
+            opened_at: None,
+            batch_size: config.batch_size,
+            seal_timeout: config.seal_timeout,
         }
     }
 
 
-    pub fn to_proposal(self) -> BatchProposal {
-        BatchProposal::new(self.payloads, self.batch_id)
+    pub fn to_proposal(self, broker_id: BrokerId, signature_timeout: Duration) -> Result<BatchProposal,BatchError> {
+        BatchProposal::with_tree(self.submissions, self.pks, self.merkle.finish(), self.batch_id, broker_id, signature_timeout)
     }
 
-    pub fn add(&mut self, addr: sockaddr_in, client_id: u64, payload: Payload) -> PositionInBatch{ 
-        assert!(self.addrs.len() == self.clients_ids.len() && self.payloads.len() == self.clients_ids.len());
+    pub fn add(&mut self, addr: sockaddr_in, client_id: u64, submission: Submission, pk: PublicKey) -> PositionInBatch{ 
+        assert!(self.addrs.len() == self.clients_ids.len() && self.submissions.len() == self.clients_ids.len());
         assert!(self.addrs.len() == self.size as usize);
 
         self.addrs.push(addr);
         self.clients_ids.push(client_id);
-        self.payloads.push(payload);
+        self.merkle.push(&submission.payload.to_bytes());
+        self.submissions.push(submission);
+        self.pks.push(pk);
         self.size += 1;
+        if self.opened_at.is_none() {
+            self.opened_at = Some(SystemTime::now());
+        }
 
         let pos = (self.size - 1) as usize;
         return pos
@@ -110,145 +246,306 @@ impl BatchConstruction {
     pub fn get_batch_id(&self) -> BatchId {
         self.batch_id
     }
+
+    /// A batch is sealed once it is full, or once its first payload has waited
+    /// for `seal_timeout`. An empty batch is never sealed.
+    pub fn is_full(&self) -> bool {
+        self.size >= self.batch_size
+    }
+
+    pub fn should_seal(&self, now: SystemTime) -> bool {
+        if self.is_full() {
+            return true
+        }
+
+        match self.opened_at {
+            Some(opened) => now.duration_since(opened).unwrap_or_default() >= self.seal_timeout,
+            None => false,
+        }
+    }
 }
 
 
 impl BatchManager {
-    pub fn new() -> Self {
-        Self {
-            batches: Vec::new(),
-            batch_id: 0
-        }
+    pub fn new(broker_id: BrokerId, config: BatchConfig) -> Result<Self,BatchError> {
+        config.validate()?;
+
+        Ok(Self {
+            batches: BTreeMap::new(),
+            open: Vec::new(),
+            next_open: 0,
+            next_batch_id: 0,
+            broker_id,
+            config,
+        })
     }
 
+    pub fn get_config(&self) -> &BatchConfig {
+        &self.config
+    }
+
+    pub fn get_broker_id(&self) -> BrokerId {
+        self.broker_id
+    }
+
+    /// Opens new batches in construction until there are `constructions` of them
     pub fn add_batch(&mut self) {
-        if !self.batches.is_empty() {
-            self.increment_batch_id();
+        while self.open.len() < self.config.constructions {
+            let batch_id = self.next_batch_id;
+            self.next_batch_id += 1;
+
+            let wip = BatchConstruction::new(batch_id, &self.config);
+            self.batches.insert(batch_id, BatchType::Construction(wip));
+            self.open.push(batch_id);
         }
-        
-        let wip = BatchConstruction::new(self.batch_id);
-        self.batches.push(BatchType::Construction(wip));
     }
 
-    pub fn add_to_construction(&mut self,addr: sockaddr_in, client_id: u64, payload: Payload) -> (BatchId, PositionInBatch, Option<(Vec<sockaddr_in>,MerkleTree,Vec<u64>)>){ 
+    /// The batch and the position the next payload would get from `add_to_construction`,
+    /// so that the server can write the assignment ahead before making it
+    pub fn next_slot(&self) -> Result<(BatchId, PositionInBatch),BatchError> {
+        if self.open.is_empty() {
+            return Err(BatchError::UnknownBatch(self.next_batch_id));
+        }
+
+        (0..self.open.len())
+            .map(|i| self.open[(self.next_open + i) % self.open.len()])
+            .find_map(|batch_id| match self.batches.get(&batch_id) {
+                Some(BatchType::Construction(wip)) if !wip.is_full() => Some((batch_id, wip.get_size())),
+                _ => None,
+            })
+            .ok_or(BatchError::PipelineFull)
+    }
+
+    pub fn add_to_construction(&mut self,addr: sockaddr_in, client_id: u64, submission: Submission, pk: PublicKey) -> Result<(BatchId, PositionInBatch, Option<SealedBatch>),BatchError>{ 
         
-        /*First we check if the current batch in construction is full.
-        If it is the case, we create a new batch in construction and return 
-        the batch proposal to the server so that it can the proofs of inclusions
-        to the clients 
+        /*We add the payload to the next batch in construction that is not full, in turn.
+        If this fills the batch, it is sealed right away (unless too many proposals are 
+        waiting for signatures): a new batch in construction is opened and the sealed batch 
+        is returned to the server so that it can send the proofs of inclusions to the clients 
          */
-        let mut idx_wip = self.batch_id;
-        let mut tuple: Option<(Vec<sockaddr_in>,MerkleTree,Vec<u64>)> = None;
-        let mut has_created = false;
-        let batch = &self.batches[idx_wip as usize];
-        
+        let (idx_wip, _) = self.next_slot()?;
+        let pos = match self.batches.get_mut(&idx_wip) {
+            Some(BatchType::Construction(wip)) => wip.add(addr, client_id, submission, pk),
+            Some(_) => return Err(BatchError::WrongState(idx_wip)),
+            None => return Err(BatchError::UnknownBatch(idx_wip)),
+        };
+        self.next_open = (self.open.iter().position(|id| *id == idx_wip).unwrap_or(0) + 1) % self.open.len();
+
+        let sealed = self.seal_if_ready(idx_wip, SystemTime::now());
+        Ok((idx_wip,pos,sealed))
+    }
 
-        match batch {
-            BatchType::Construction(wip) => {
-                if wip.get_size() >= BATCH_SIZE as usize{
-                    let (batch_id, vec_addrs,merkle,client_ids) = self.construction_to_proposal(idx_wip);
-                    tuple = Some((vec_addrs,merkle,client_ids));
-                    idx_wip = batch_id;
-                    has_created = true;
-                }
-            },
-            _ => todo!(),
+    /// Makes the batches opened from now on get ids above `batch_id`. After a restart,
+    /// the ids of the batches the broker already used must not be given again.
+    pub fn skip_past(&mut self, batch_id: BatchId) {
+        self.next_batch_id = self.next_batch_id.max(batch_id + 1);
+    }
+
+    /// Puts back a proposal that was sealed before a restart, from the submissions of its
+    /// clients in the order of their positions. The root, and so the signing context, is the
+    /// same as before the restart. The signature timeout is not started.
+    pub fn restore_proposal(&mut self, batch_id: BatchId, entries: Vec<(sockaddr_in, u64, Submission, PublicKey)>) -> Result<SealedBatch,BatchError> {
+        if self.batches.contains_key(&batch_id) {
+            return Err(BatchError::WrongState(batch_id));
+        }
+        if entries.is_empty() {
+            return Err(BatchError::EmptyBatch(batch_id));
         }
 
-        if has_created {
-            self.add_batch();
+        let mut wip = BatchConstruction::new(batch_id, &self.config);
+        for (addr, client_id, submission, pk) in entries {
+            wip.add(addr, client_id, submission, pk);
         }
+        self.batches.insert(batch_id, BatchType::Construction(wip));
+        self.skip_past(batch_id);
+        self.construction_to_proposal(batch_id)
+    }
+
+    /// Seals the batches in construction that are full or whose seal timeout expired, oldest first.
+    /// The server has to call this periodically, otherwise a partial batch 
+    /// would wait for the next payload to be sealed.
+    pub fn poll_seal(&mut self, now: SystemTime) -> Vec<SealedBatch> {
+        let open = self.open.clone();
+        open.into_iter()
+            .filter_map(|batch_id| self.seal_if_ready(batch_id, now))
+            .collect()
+    }
+
+    pub fn proposals_in_flight(&self) -> usize {
+        self.batches
+            .values()
+            .filter(|batch| matches!(batch, BatchType::Proposal(_)))
+            .count()
+    }
 
-        let batch_to_add = &mut self.batches[idx_wip as usize];
-        let mut pos = 0;
-        if let BatchType::Construction(wip) = batch_to_add {
-            pos = wip.add(addr, client_id, payload);
+    fn seal_if_ready(&mut self, batch_id: BatchId, now: SystemTime) -> Option<SealedBatch> {
+        let should_seal = match self.batches.get(&batch_id) {
+            Some(BatchType::Construction(wip)) => wip.should_seal(now),
+            _ => false,
+        };
+
+        if !should_seal || self.proposals_in_flight() >= self.config.max_proposals {
+            return None
         }
 
-        (idx_wip,pos,tuple)
+        let sealed = self.construction_to_proposal(batch_id).ok()?;
+        self.add_batch();
+        Some(sealed)
     }
 
 
     // pub fn add_to_proposal(&mut self, batch_id:usize, pos:usize,pk: PublicKey, sig: Signature) {
 
-    pub fn add_to_proposal(&mut self, batch_id:usize, pos:usize, client_id: usize, sig: Signature, pk: PublicKey, c: &mut i32) {
-        assert!(batch_id <= self.batches.len());
-        
-        let batch = &mut self.batches[batch_id];
+    /// Adds the signature of the client at `pos` to the proposal. Returns true if this
+    /// signature completed the quorum of the proposal, which is then distilled right away.
+    /// The signature is checked before it takes the position: otherwise anyone claiming the
+    /// id of the client could fill its position with garbage and lock the client out.
+    pub fn add_to_proposal(&mut self, batch_id:usize, pos:usize, sig: Signature, pk: PublicKey) -> Result<bool,BatchError> {
+        let batch = self.batches.get_mut(&batch_id).ok_or(BatchError::UnknownBatch(batch_id))?;
         match batch {
             BatchType::Proposal(proposal) => {
-                match proposal.start_time {
-                    Some(start) => {
-                        if start.elapsed().unwrap() > proposal.timeout_duration {
-                            if !proposal.has_timeout {
-                                println!("finished batch, timeout expired: {}",c);
-                                proposal.has_timeout = true;
-                            }
-                            self.proposal_to_distilled(batch_id);
-                            return;
-                        }
-                    },
-                    None => {}
-                } 
-                *c+=1;
+                if pos >= proposal.bitmap.len() {
+                    return Err(BatchError::PositionOutOfRange { batch_id, pos });
+                }
+                if proposal.bitmap[pos] {
+                    return Err(BatchError::DuplicateSignature { batch_id, pos });
+                }
+                /*signatures arriving after the timeout are too late,
+                the batch will be distilled by the next call to poll_expired */
+                if proposal.is_expired(SystemTime::now()) {
+                    return Ok(false);
+                }
+                if !proposal.get_signing_context().verify(&sig, &pk) {
+                    return Err(BatchError::InvalidSignature { batch_id, pos });
+                }
                 proposal.list_sigs.push(sig);
                 proposal.lists_pks.push(pk);
                 proposal.bitmap[pos] = true;
-                proposal.pos_to_cliendid.push(client_id);
+                proposal.signed += 1;
 
+                if proposal.has_quorum(self.config.quorum_fraction) {
+                    self.proposal_to_distilled(batch_id)?;
+                    return Ok(true);
+                }
+                Ok(false)
             },
-            _ => (),
+            _ => Err(BatchError::WrongState(batch_id)),
         }
     }
 
-    pub fn add_start_time(&mut self, batch_id: BatchId) {
-        assert!(batch_id < self.batches.len());
+    /// Distills every proposal whose signature timeout expired at `now` and returns their ids.
+    /// The server has to call this periodically: a proposal whose clients went silent
+    /// is distilled all the same.
+    pub fn poll_expired(&mut self, now: SystemTime) -> Vec<BatchId> {
+        let expired: Vec<BatchId> = self.batches
+            .iter()
+            .filter_map(|(batch_id,batch)| match batch {
+                BatchType::Proposal(proposal) if proposal.is_expired(now) => Some(*batch_id),
+                _ => None,
+            })
+            .collect();
+
+        expired
+            .into_iter()
+            .filter(|batch_id| self.proposal_to_distilled(*batch_id).is_ok())
+            .collect()
+    }
 
-        let batch = &mut self.batches[batch_id];
-        if let BatchType::Proposal(proposal) = batch {
-            assert!(proposal.start_time.is_none());
-            let now = SystemTime::now();
-            println!("Start time: {:?}",now);
-            proposal.start_time = Some(now);
+    pub fn get_distilled(&self, batch_id: BatchId) -> Option<&DistilledBatch> {
+        match self.batches.get(&batch_id) {
+            Some(BatchType::DistilledBatch(distilled)) => Some(distilled),
+            _ => None,
         }
     }
 
-    pub fn construction_to_proposal(&mut self,batch_id: usize) -> (BatchId,Vec<sockaddr_in>,MerkleTree,Vec<u64>){
-        if let Some(batch) = self.batches.get_mut(batch_id as usize) {
-            match mem::replace(batch, BatchType::Proposal(BatchProposal::new(vec![], 0))) {
-                BatchType::Construction(wip) => {
-                    let addrs = wip.addrs.clone();
-                    let client_ids = wip.clients_ids.clone();
-                    let batch_id = wip.batch_id + 1;
-                    let proposal: BatchProposal = wip.to_proposal();
-                    let merkle = proposal.merkle.clone();
-                    *batch = BatchType::Proposal(proposal); 
-                    return (batch_id,addrs,merkle,client_ids)
-                },
-                _ => todo!(),
+    /// Removes the distilled batches from the manager and hands them off in the order
+    /// of their ids. The caller is responsible for delivering or persisting them:
+    /// once drained, a batch can no longer be found by its id.
+    /// A distilled batch is only handed off once every older batch that holds payloads is
+    /// handed off too, so that the ids a downstream server receives keep growing.
+    pub fn drain_distilled(&mut self) -> Vec<DistilledBatch> {
+        let distilled: Vec<BatchId> = self.batches
+            .iter()
+            .take_while(|(_,batch)| match batch {
+                BatchType::Construction(wip) => wip.get_size() == 0,
+                BatchType::Proposal(_) => false,
+                BatchType::DistilledBatch(_) => true,
+            })
+            .filter(|(_,batch)| matches!(batch, BatchType::DistilledBatch(_)))
+            .map(|(batch_id,_)| *batch_id)
+            .collect();
+
+        /*The empty batches in construction older than the last batch handed off are replaced
+        by new ones: a payload added to them would be handed off out of order */
+        if let Some(last) = distilled.last() {
+            let skipped: Vec<BatchId> = self.open.iter().copied().filter(|batch_id| batch_id < last).collect();
+            if !skipped.is_empty() {
+                for batch_id in &skipped {
+                    self.batches.remove(batch_id);
+                }
+                self.open.retain(|batch_id| batch_id > last);
+                self.add_batch();
+                self.next_open %= self.open.len();
             }
-        }else {
-            (0,vec![],MerkleTree::new(&vec![]),vec![])
         }
-    }
- 
 
-    pub fn proposal_to_distilled(&mut self, batch_id: usize) {
-        assert!(batch_id < self.batch_id);
+        distilled
+            .into_iter()
+            .filter_map(|batch_id| match self.batches.remove(&batch_id) {
+                Some(BatchType::DistilledBatch(distilled)) => Some(distilled),
+                _ => None,
+            })
+            .collect()
+    }
 
-        if let Some(batch) = self.batches.get_mut(batch_id as usize) {
-            match mem::replace(batch, BatchType::DistilledBatch(DistilledBatch::new(vec![], vec![],0))){
-                BatchType::Proposal(proposal) => {
-                    let distilled = proposal.to_distilled();
-                    *batch = BatchType::DistilledBatch(distilled);
-                },
-                _ => todo!(),
-            }
+    /// Starts the signature timeout of the proposal. It can only be started once.
+    pub fn add_start_time(&mut self, batch_id: BatchId) -> Result<(),BatchError> {
+        let batch = self.batches.get_mut(&batch_id).ok_or(BatchError::UnknownBatch(batch_id))?;
+        match batch {
+            BatchType::Proposal(proposal) if proposal.start_time.is_none() => {
+                proposal.start_time = Some(SystemTime::now());
+                Ok(())
+            },
+            _ => Err(BatchError::WrongState(batch_id)),
         }
     }
 
-    fn increment_batch_id(&mut self) {
-        self.batch_id += 1;
+    /*The batch is taken out of the map while it changes state, and put back in its new state */
+    pub fn construction_to_proposal(&mut self,batch_id: usize) -> Result<SealedBatch,BatchError>{
+        let wip = match self.batches.remove(&batch_id) {
+            Some(BatchType::Construction(wip)) if wip.get_size() > 0 => wip,
+            Some(batch) => {
+                let empty = matches!(batch, BatchType::Construction(_));
+                self.batches.insert(batch_id, batch);
+                return Err(if empty { BatchError::EmptyBatch(batch_id) } else { BatchError::WrongState(batch_id) });
+            },
+            None => return Err(BatchError::UnknownBatch(batch_id)),
+        };
+
+        let addrs = wip.addrs.clone();
+        let client_ids = wip.clients_ids.clone();
+        let proposal: BatchProposal = wip.to_proposal(self.broker_id, self.config.signature_timeout)?;
+        let context = proposal.get_signing_context();
+        let merkle = proposal.merkle.clone();
+        self.batches.insert(batch_id, BatchType::Proposal(proposal));
+        self.open.retain(|id| *id != batch_id);
+        self.next_open = 0;
+        Ok(SealedBatch { context, addrs, merkle, client_ids })
+    }
+ 
+
+    pub fn proposal_to_distilled(&mut self, batch_id: usize) -> Result<(),BatchError> {
+        match self.batches.remove(&batch_id) {
+            Some(BatchType::Proposal(proposal)) => {
+                self.batches.insert(batch_id, BatchType::DistilledBatch(proposal.to_distilled()));
+                Ok(())
+            },
+            Some(batch) => {
+                self.batches.insert(batch_id, batch);
+                Err(BatchError::WrongState(batch_id))
+            },
+            None => Err(BatchError::UnknownBatch(batch_id)),
+        }
     }
 }
 
@@ -279,7 +576,7 @@ impl Payload {
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<Self,NotAPayload> {
-        if (buf.len() < 24 || buf.len() > 1024){
+        if buf.len() < 24 {
             println!("size: {}",buf.len());
             return Err(NotAPayload)
         }
@@ -287,6 +584,9 @@ impl Payload {
         let num_id = u64::from_be_bytes(buf[..8].try_into().expect("slice incorrect size"));
         let seq_num = u64::from_be_bytes(buf[8..16].try_into().expect("slice incorrect size"));
         let msg_len: usize = usize::from_be_bytes(buf[16..24].try_into().expect("slice incorrect size"));
+        if msg_len > buf.len() - 24 {
+            return Err(NotAPayload)
+        }
         let mut message = vec![0;msg_len];
         message.copy_from_slice(&buf[24..(24+msg_len)]);
 
@@ -301,49 +601,193 @@ impl Payload {
 }
 
 
+impl Submission {
+    pub fn new(payload: Payload, signature: Signature) -> Self {
+        Self {
+            payload,
+            signature,
+        }
+    }
+
+    /// Signs `payload` for `broker_id` with the secret key of its client
+    pub fn sign(payload: Payload, sk: &SecretKey, broker_id: BrokerId) -> Self {
+        let signature = sk.sign(&payload_message(broker_id, &payload), DST, &[]);
+        Self::new(payload, signature)
+    }
+
+    pub fn verify(&self, broker_id: BrokerId, pk: &PublicKey) -> bool {
+        verify_payload(broker_id, &self.payload, &self.signature, pk)
+    }
+
+    /// The serialized payload followed by the compressed signature (96 bytes)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.payload.to_bytes();
+        buf.extend_from_slice(&self.signature.compress());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self,NotAPayload> {
+        let payload = Payload::from_bytes(buf)?;
+        let start = 24 + payload.message.len();
+        if buf.len() < start + 96 {
+            return Err(NotAPayload)
+        }
+
+        match Signature::from_bytes(&buf[start..start+96]) {
+            Ok(signature) => Ok(Self::new(payload, signature)),
+            Err(_) => Err(NotAPayload),
+        }
+    }
+}
+
+
+impl RootSignature {
+    /// The position (8 bytes, big endian) followed by the compressed signature (96 bytes)
+    pub fn to_payload(&self, client_id: NumericalIdentifier) -> Payload {
+        let mut message = Vec::with_capacity(8 + 96);
+        message.extend_from_slice(&(self.pos as u64).to_be_bytes());
+        message.extend_from_slice(&self.signature.compress());
+        Payload::new(client_id, 0, message)
+    }
+
+    pub fn from_payload(payload: &Payload) -> Result<Self,NotAPayload> {
+        if payload.message.len() != 8 + 96 {
+            return Err(NotAPayload)
+        }
+
+        let pos = u64::from_be_bytes(payload.message[..8].try_into().expect("slice incorrect size")) as PositionInBatch;
+        match Signature::from_bytes(&payload.message[8..]) {
+            Ok(signature) => Ok(Self { pos, signature }),
+            Err(_) => Err(NotAPayload),
+        }
+    }
+}
+
+
 impl BatchProposal{
 
-    pub fn new(payloads: Vec<Payload>,batch_id:BatchId) -> Self {
-        // assert!(!payloads.is_empty());
+    pub fn new(submissions: Vec<Submission>,pks: Vec<PublicKey>,batch_id:BatchId,broker_id: BrokerId,timeout_duration: Duration) -> Result<Self,BatchError> {
+        let mut builder = MerkleBuilder::new();
+        for submission in &submissions {
+            builder.push(&submission.payload.to_bytes());
+        }
+        Self::with_tree(submissions, pks, builder.finish(), batch_id, broker_id, timeout_duration)
+    }
 
+    /// A proposal whose Merkle tree was built as its payloads arrived (see `MerkleBuilder`).
+    /// The leaves of `merkletree` must be the payloads of `submissions`, in the same order.
+    pub fn with_tree(submissions: Vec<Submission>,pks: Vec<PublicKey>,merkletree: MerkleTree,batch_id:BatchId,broker_id: BrokerId,timeout_duration: Duration) -> Result<Self,BatchError> {
+        if submissions.len() != pks.len() || submissions.len() != merkletree.tree[0].len() {
+            return Err(BatchError::MismatchedLengths(batch_id));
+        }
 
-        let leaves: Vec<Vec<u8>> = payloads
-        .iter()
-        .map(|x| x.to_bytes())
-        .collect();
-    
-        let leaves_slice: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
-        let bitmap = vec![false;leaves.len()];
-        
-        let merkletree = MerkleTree::new(&leaves_slice);  
+        let (payloads, individual_sigs): (Vec<Payload>,Vec<Signature>) = submissions
+        .into_iter()
+        .map(|s| (s.payload, s.signature))
+        .unzip();
+
+        let bitmap = vec![false;payloads.len()];
 
-        Self { 
+        Ok(Self { 
             batch_id,
+            broker_id,
             merkle: merkletree,
             bitmap,
+            payloads,
+            individual_sigs,
+            pks,
             list_sigs: vec![],
             lists_pks: vec![],
-            pos_to_cliendid: vec![],
+            signed: 0,
             start_time: None,
-            timeout_duration: Duration::from_millis(TIMEOUT_DURATION_BATCH),
-            has_timeout: false
-        }
+            timeout_duration,
+        })
     }
 
     fn to_distilled(self) ->  DistilledBatch {
         println!("transformed proposal to distilled batch");
 
-        DistilledBatch::new(self.list_sigs,self.lists_pks, self.batch_id)
+        /*Every signature of the root was verified by add_to_proposal before it was
+        added to the list, so they all go into the aggregate */
+        let context = self.get_signing_context();
+        let bitmap = self.bitmap;
+        let sigtree = SignatureTree::new(self.list_sigs, self.lists_pks);
+
+        /*Stragglers are kept only if their individual signature authenticates their payload */
+        let exceptions: Vec<Exception> = (0..bitmap.len())
+            .filter(|pos| !bitmap[*pos])
+            .filter(|pos| verify_payload(self.broker_id, &self.payloads[*pos], &self.individual_sigs[*pos], &self.pks[*pos]))
+            .map(|position| Exception { position, signature: self.individual_sigs[position] })
+            .collect();
+
+        DistilledBatch::new(context, self.payloads, bitmap, sigtree, exceptions)
+    }
+
+    /// A proposal expires `timeout_duration` after the proofs of inclusion were sent.
+    /// Before that (no start time yet) it cannot expire.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        match self.start_time {
+            Some(start) => now.duration_since(start).unwrap_or_default() >= self.timeout_duration,
+            None => false,
+        }
+    }
+
+    /// True once at least `quorum_fraction` of the clients of the batch signed its root
+    pub fn has_quorum(&self, quorum_fraction: f64) -> bool {
+        let quorum = (quorum_fraction * self.bitmap.len() as f64).ceil() as usize;
+        !self.bitmap.is_empty() && self.signed >= quorum
+    }
+
+    /// The message every client of this batch has to sign
+    pub fn get_signing_context(&self) -> SigningContext {
+        SigningContext::new(self.broker_id, self.batch_id, self.merkle.get_root_hash())
     }
 }
 
 
 impl DistilledBatch { 
-        pub fn new(list_sigs:Vec<Signature>, list_pks: Vec<PublicKey>, batch_id: BatchId) -> Self{
+        pub fn new(context: SigningContext, payloads: Vec<Payload>, bitmap: Vec<bool>, sigtree: SignatureTree, exceptions: Vec<Exception>) -> Self{
+            assert!(payloads.len() == bitmap.len());
     
             Self{
-                batch_id,
-                sigtree: SignatureTree::new(list_sigs, list_pks)
+                batch_id: context.batch_id,
+                context,
+                payloads,
+                bitmap,
+                sigtree,
+                exceptions,
             }
         }
+
+        pub fn empty(context: SigningContext) -> Self {
+            Self::new(context, vec![], vec![], SignatureTree::new(vec![], vec![]), vec![])
+        }
+
+        pub fn get_batch_id(&self) -> BatchId {
+            self.batch_id
+        }
+
+        pub fn get_payloads(&self) -> &[Payload] {
+            &self.payloads
+        }
+
+        /// The payloads authenticated either by the aggregate signature or by an exception,
+        /// in the order of the batch
+        pub fn delivered_payloads(&self) -> Vec<&Payload> {
+            delivered_payloads(&self.payloads, &self.bitmap, &self.exceptions)
+        }
+
+        pub fn get_root(&self) -> Hash {
+            self.context.root
+        }
+
+        pub fn get_signing_context(&self) -> SigningContext {
+            self.context
+        }
+
+        /// Returns the indices (in order of arrival) of the signatures that
+        /// are not valid signatures of the signing context of this batch
+        pub fn check(&self) -> Vec<usize> {
+            self.sigtree.check(&self.context.to_bytes())
+        }
     }
\ No newline at end of file
@@ -0,0 +1,398 @@
+use core::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use blake3::Hash;
+use blst::min_pk::{PublicKey, Signature};
+use blst::BLST_ERROR;
+
+use crate::batch::{self, BatchError, DistilledBatch, Exception, Payload};
+use crate::codec::Cursor;
+use crate::merkle::MerkleTree;
+use crate::registry::ClientRegistry;
+use crate::signing::{verify_payload, SigningContext, DST};
+
+/// First bytes of every batch log, and of every stream of batches sent to a downstream server
+pub(crate) const LOG_TAG: &[u8;16] = b"RAINFALL_BATCHLG";
+pub(crate) const LOG_VERSION: u8 = 1;
+/// tag (16 bytes) + version (1 byte)
+const HEADER_SIZE: usize = 17;
+const SIGNATURE_SIZE: usize = 96;
+/// Bound on the body of a record of the batch log. A batch of the largest size the broker
+/// can be configured with stays far below it.
+const MAX_RECORD_LEN: u64 = 1 << 30;
+
+/// Errors of the batch log and of the write-ahead log of the broker, which share their framing
+#[derive(Debug)]
+pub enum LogError {
+    /// The file does not start with the header of a log of the expected kind and version
+    UnexpectedHeader,
+    /// The checksum of the record does not match, or its content can't be decoded
+    Corrupted { record: usize },
+    /// The log ends in the middle of a record, e.g. after a crash during `append`
+    Truncated { record: usize },
+    /// The logs can't be replayed with this configuration (see `BatchConfig::validate`)
+    Config(BatchError),
+    Io(io::Error),
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::UnexpectedHeader => write!(f, "Expected a log but got something else"),
+            LogError::Corrupted { record } => write!(f, "record {} of the log is corrupted", record),
+            LogError::Truncated { record } => write!(f, "the log ends in the middle of record {}", record),
+            LogError::Config(e) => write!(f, "log: {}", e),
+            LogError::Io(e) => write!(f, "log: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for LogError {
+    fn from(e: io::Error) -> Self {
+        LogError::Io(e)
+    }
+}
+
+impl From<BatchError> for LogError {
+    fn from(e: BatchError) -> Self {
+        LogError::Config(e)
+    }
+}
+
+/// What the log keeps of a distilled batch: everything needed to check, without
+/// the broker, which payloads were delivered and that their clients approved them.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct LoggedBatch {
+    pub context: SigningContext,
+    pub payloads: Vec<Payload>,
+    pub bitmap: Vec<bool>,
+    /// Aggregate of the signatures of the positions set in the bitmap, None if nobody signed
+    pub aggregate: Option<Signature>,
+    pub exceptions: Vec<Exception>,
+}
+
+impl LoggedBatch {
+    pub fn from_distilled(distilled: &DistilledBatch) -> Self {
+        let signed = distilled.bitmap.iter().any(|b| *b);
+        Self {
+            context: distilled.get_signing_context(),
+            payloads: distilled.get_payloads().to_vec(),
+            bitmap: distilled.bitmap.clone(),
+            aggregate: if signed { distilled.sigtree.aggregate_signature() } else { None },
+            exceptions: distilled.exceptions.clone(),
+        }
+    }
+
+    /// The payloads authenticated either by the aggregate signature or by an exception,
+    /// in the order of the batch
+    pub fn delivered_payloads(&self) -> Vec<&Payload> {
+        batch::delivered_payloads(&self.payloads, &self.bitmap, &self.exceptions)
+    }
+
+    /// Checks that the payloads are the leaves of the Merkle tree whose root the clients signed
+    pub fn check_root(&self) -> bool {
+        if self.payloads.is_empty() {
+            return false
+        }
+
+        let leaves: Vec<Vec<u8>> = self.payloads.iter().map(|p| p.to_bytes()).collect();
+        let leaves: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
+        MerkleTree::new(&leaves).get_root_hash() == self.context.root
+    }
+
+    /// Checks the aggregate signature against the keys of the clients set in the bitmap,
+    /// and each exception against the key of the client of its payload
+    pub fn verify(&self, registry: &ClientRegistry) -> bool {
+        if self.bitmap.len() != self.payloads.len() {
+            return false
+        }
+
+        let pk_of = |pos: usize| registry.get_pk(self.payloads[pos].num_id).ok();
+
+        let signers: Option<Vec<PublicKey>> = self.bitmap.iter()
+            .enumerate()
+            .filter(|(_,signed)| **signed)
+            .map(|(pos,_)| pk_of(pos))
+            .collect();
+        let signers = match signers {
+            Some(signers) => signers,
+            None => return false,
+        };
+
+        let aggregate_valid = match self.aggregate {
+            Some(aggregate) if !signers.is_empty() => {
+                let pks: Vec<&PublicKey> = signers.iter().collect();
+                aggregate.fast_aggregate_verify(true, &self.context.to_bytes(), DST, &pks) == BLST_ERROR::BLST_SUCCESS
+            },
+            None => signers.is_empty(),
+            Some(_) => false,
+        };
+
+        aggregate_valid && self.exceptions.iter().all(|exception| {
+            exception.position < self.payloads.len() && !self.bitmap[exception.position] && match pk_of(exception.position) {
+                Some(pk) => verify_payload(self.context.broker_id, &self.payloads[exception.position], &exception.signature, &pk),
+                None => false,
+            }
+        })
+    }
+
+    /*The body of the record (see `frame`) is made of the signing context (which holds the
+    broker id, the batch id and the root), the number of payloads followed by each payload
+    prefixed by its length, the bitmap (one bit per payload), a byte telling whether an
+    aggregate signature follows, and the number of exceptions followed by each exception
+    (position then signature). Every integer is big endian. */
+    pub fn to_record(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&self.context.to_bytes());
+
+        body.extend_from_slice(&(self.payloads.len() as u64).to_be_bytes());
+        for payload in &self.payloads {
+            let bytes = payload.to_bytes();
+            body.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+            body.extend_from_slice(&bytes);
+        }
+
+        let mut bitmap = vec![0u8;self.bitmap.len().div_ceil(8)];
+        for (pos,signed) in self.bitmap.iter().enumerate() {
+            bitmap[pos / 8] |= (*signed as u8) << (pos % 8);
+        }
+        body.extend_from_slice(&bitmap);
+
+        match self.aggregate {
+            Some(aggregate) => {
+                body.push(1);
+                body.extend_from_slice(&aggregate.compress());
+            },
+            None => body.push(0),
+        }
+
+        body.extend_from_slice(&(self.exceptions.len() as u64).to_be_bytes());
+        for exception in &self.exceptions {
+            body.extend_from_slice(&(exception.position as u64).to_be_bytes());
+            body.extend_from_slice(&exception.signature.compress());
+        }
+
+        frame(&body)
+    }
+
+    /// Decodes the body of a record whose checksum was already checked
+    fn from_body(body: &[u8]) -> Option<Self> {
+        let mut cursor = Cursor::new(body);
+
+        let context = SigningContext::from_bytes(cursor.take(SigningContext::SIZE)?).ok()?;
+
+        let num_payloads = cursor.take_u64()? as usize;
+        let mut payloads = Vec::new();
+        for _ in 0..num_payloads {
+            let len = cursor.take_u64()? as usize;
+            payloads.push(Payload::from_bytes(cursor.take(len)?).ok()?);
+        }
+
+        let bitmap_bytes = cursor.take(num_payloads.div_ceil(8))?;
+        /* The padding bits of the last byte must be zero */
+        if !num_payloads.is_multiple_of(8) && bitmap_bytes[num_payloads / 8] >> (num_payloads % 8) != 0 {
+            return None
+        }
+        let bitmap: Vec<bool> = (0..num_payloads)
+            .map(|pos| (bitmap_bytes[pos / 8] >> (pos % 8)) & 1 == 1)
+            .collect();
+
+        let aggregate = match cursor.take(1)?[0] {
+            0 => None,
+            1 => Some(Signature::from_bytes(cursor.take(SIGNATURE_SIZE)?).ok()?),
+            _ => return None,
+        };
+
+        let num_exceptions = cursor.take_u64()? as usize;
+        let mut exceptions = Vec::new();
+        let mut excepted = vec![false; num_payloads];
+        for _ in 0..num_exceptions {
+            let position = cursor.take_u64()? as usize;
+            /* An exception covers a payload of the batch that is not already in the bitmap,
+            and at most once */
+            if position >= num_payloads || bitmap[position] || excepted[position] {
+                return None
+            }
+            excepted[position] = true;
+            let signature = Signature::from_bytes(cursor.take(SIGNATURE_SIZE)?).ok()?;
+            exceptions.push(Exception { position, signature });
+        }
+
+        if !cursor.is_done() {
+            return None
+        }
+
+        Some(Self { context, payloads, bitmap, aggregate, exceptions })
+    }
+}
+
+/// A record as written on disk: the length of the body (8 bytes, big endian),
+/// the body and the blake3 hash of the body (32 bytes)
+pub(crate) fn frame(body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(8 + body.len() + 32);
+    record.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    record.extend_from_slice(body);
+    record.extend_from_slice(blake3::hash(body).as_bytes());
+    record
+}
+
+/// Reads the body of the next record and checks its checksum. None at the end of the log.
+/// A record is only `Truncated` if the log ends inside it and its length is one a record
+/// can have: a length above `max_len` is `Corrupted`, whatever follows it.
+pub(crate) fn read_frame<R: Read>(reader: &mut R, record: usize, max_len: u64) -> Result<Option<Vec<u8>>,LogError> {
+    let truncated = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => LogError::Truncated { record },
+        _ => LogError::Io(e),
+    };
+
+    let mut len = [0u8;8];
+    let read = reader.read(&mut len)?;
+    if read == 0 {
+        return Ok(None)
+    }
+    reader.read_exact(&mut len[read..]).map_err(truncated)?;
+    let len = u64::from_be_bytes(len);
+    /*a damaged length would otherwise make the rest of the log look like the tail of a
+    record cut short by a crash, and recovery would cut every record after it */
+    if len > max_len {
+        return Err(LogError::Corrupted { record })
+    }
+
+    /*the body is read as it comes so that a corrupted length can't make us allocate it upfront */
+    let mut body = Vec::new();
+    reader.take(len).read_to_end(&mut body)?;
+    if body.len() as u64 != len {
+        return Err(LogError::Truncated { record })
+    }
+    let mut checksum = [0u8;32];
+    reader.read_exact(&mut checksum).map_err(truncated)?;
+
+    if blake3::hash(&body) != Hash::from(checksum) {
+        return Err(LogError::Corrupted { record })
+    }
+    Ok(Some(body))
+}
+
+pub(crate) fn write_header<W: Write>(writer: &mut W, tag: &[u8;16], version: u8) -> io::Result<()> {
+    writer.write_all(tag)?;
+    writer.write_all(&[version])
+}
+
+/// Writes the header if the log is empty, and checks it otherwise
+pub(crate) fn init_log(file: &mut File, tag: &[u8;16], version: u8) -> Result<(),LogError> {
+    if file.metadata()?.len() == 0 {
+        write_header(file, tag, version)?;
+        file.sync_data()?;
+        Ok(())
+    } else {
+        check_header(file, tag, version)
+    }
+}
+
+/// Append-only log of every batch the broker distilled, in the order they were handed off
+#[derive(Debug)]
+pub struct BatchLog {
+    file: File,
+}
+
+impl BatchLog {
+    /// Opens the log for appending, creating it if it does not exist yet
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self,LogError> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        init_log(&mut file, LOG_TAG, LOG_VERSION)?;
+        Ok(Self { file })
+    }
+
+    /// The batch is on disk once this returns
+    pub fn append(&mut self, distilled: &DistilledBatch) -> Result<(),LogError> {
+        self.file.write_all(&LoggedBatch::from_distilled(distilled).to_record())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+pub(crate) fn check_header<R: Read>(reader: &mut R, tag: &[u8;16], version: u8) -> Result<(),LogError> {
+    let mut header = [0u8;HEADER_SIZE];
+    match reader.read_exact(&mut header) {
+        Ok(()) if &header[..16] == tag && header[16] == version => Ok(()),
+        Ok(()) => Err(LogError::UnexpectedHeader),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(LogError::UnexpectedHeader),
+        Err(e) => Err(LogError::Io(e)),
+    }
+}
+
+/// Reads a batch log from the start, checking the checksum of every record.
+/// The iteration stops after the first error.
+#[derive(Debug)]
+pub struct BatchLogReader<R: Read = BufReader<File>> {
+    reader: R,
+    record: usize,
+    /// Length of the log up to the end of the last record read successfully
+    valid_len: u64,
+    done: bool,
+}
+
+impl BatchLogReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self,LogError> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> BatchLogReader<R> {
+    /// Reads batches in the format of the log from anything, e.g. from the stream
+    /// the broker sends to a downstream server (see `BatchSender`)
+    pub fn from_reader(mut reader: R) -> Result<Self,LogError> {
+        check_header(&mut reader, LOG_TAG, LOG_VERSION)?;
+        Ok(Self {
+            reader,
+            record: 0,
+            valid_len: HEADER_SIZE as u64,
+            done: false,
+        })
+    }
+
+    /// Once the iteration stopped on an error, the length the log has to be truncated
+    /// to before anything is appended to it
+    pub fn valid_len(&self) -> u64 {
+        self.valid_len
+    }
+
+    fn read_record(&mut self) -> Result<Option<LoggedBatch>,LogError> {
+        let record = self.record;
+        match read_frame(&mut self.reader, record, MAX_RECORD_LEN)? {
+            Some(body) => {
+                let batch = LoggedBatch::from_body(&body).ok_or(LogError::Corrupted { record })?;
+                self.valid_len += (8 + body.len() + 32) as u64;
+                Ok(Some(batch))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl<R: Read> Iterator for BatchLogReader<R> {
+    type Item = Result<LoggedBatch,LogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+
+        match self.read_record() {
+            Ok(Some(batch)) => {
+                self.record += 1;
+                Some(Ok(batch))
+            },
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
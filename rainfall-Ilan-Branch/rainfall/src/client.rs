@@ -0,0 +1,148 @@
+use core::fmt;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::batch::{NumericalIdentifier, Payload, SequenceNumber};
+use crate::merkle::MerklePath;
+use crate::rejection::{Rejection, RejectionReason, Resubmit};
+use crate::signing::{BrokerId, SigningContext};
+
+/// Misbehaviours of the broker that a client can detect on its own.
+/// When one of them happens the client must not sign anything.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum BrokerMisbehaviour {
+    /// The batch was proposed by another broker than the one the client submitted to
+    WrongBroker { client_id: NumericalIdentifier, broker_id: BrokerId },
+    /// None of the payloads the client is waiting on are included under the proposed root
+    InvalidInclusionProof { client_id: NumericalIdentifier, context: SigningContext },
+    /// The broker rejected a payload the client is not waiting on, or claims it delivered
+    /// a sequence number the client never gave out
+    InvalidRejection { client_id: NumericalIdentifier, seq_num: SequenceNumber },
+}
+
+impl fmt::Display for BrokerMisbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BrokerMisbehaviour::WrongBroker { client_id, broker_id } => {
+                write!(f, "client {} received a batch from unexpected broker {}", client_id, broker_id)
+            },
+            BrokerMisbehaviour::InvalidInclusionProof { client_id, context } => {
+                write!(f, "client {} received an invalid proof of inclusion for batch {} (root {})", client_id, context.batch_id, context.root)
+            },
+            BrokerMisbehaviour::InvalidRejection { client_id, seq_num } => {
+                write!(f, "client {} received a rejection of sequence number {} it can't have been sent", client_id, seq_num)
+            },
+        }
+    }
+}
+
+/// Keeps the bytes of every payload a client submitted until the broker proves
+/// they were included in a batch. The proofs of inclusion are checked against
+/// those bytes before the client agrees to sign the root of the batch.
+/// It also gives out the sequence numbers of the payloads, which the broker
+/// expects to increase for each client.
+#[derive(Debug)]
+pub struct PendingPayloads {
+    broker_id: BrokerId,
+    pending: HashMap<NumericalIdentifier, BTreeMap<SequenceNumber, Vec<u8>>>,
+    next_seq: HashMap<NumericalIdentifier, SequenceNumber>,
+}
+
+impl PendingPayloads {
+    pub fn new(broker_id: BrokerId) -> Self {
+        Self {
+            broker_id,
+            pending: HashMap::new(),
+            next_seq: HashMap::new(),
+        }
+    }
+
+    /// A payload of `client_id` with the next sequence number of that client
+    pub fn next_payload(&mut self, client_id: NumericalIdentifier, message: Vec<u8>) -> Payload {
+        let seq_num = self.next_seq.entry(client_id).or_insert(0);
+        let payload = Payload::new(client_id, *seq_num, message);
+        *seq_num += 1;
+        payload
+    }
+
+    /// The broker already delivered a payload of the client with a sequence number at least
+    /// `last_delivered`: the pending payloads up to it will never be included. A rejection of
+    /// a payload sent while another one was in flight, or while the pipeline of the broker was
+    /// full, changes nothing: the payload stays pending.
+    ///
+    /// The rejection must be one checked against the key of the broker by `Rejection::from_bytes`.
+    /// It is refused, and nothing is dropped, if the rejected payload is not pending (e.g. a
+    /// rejection sent again long after) or if `last_delivered` was never given out by the client.
+    pub fn resync(&mut self, rejection: &Rejection) -> Result<(),BrokerMisbehaviour> {
+        let (client_id, seq_num) = (rejection.client_id, rejection.seq_num);
+        let invalid = BrokerMisbehaviour::InvalidRejection { client_id, seq_num };
+        let pending = self.pending.get_mut(&client_id).filter(|p| p.contains_key(&seq_num)).ok_or(invalid.clone())?;
+        let last_delivered = match rejection.reason {
+            RejectionReason::Stale { last_delivered } => last_delivered,
+            RejectionReason::InFlight { .. } | RejectionReason::PipelineFull => return Ok(()),
+        };
+        if last_delivered >= self.next_seq.get(&client_id).copied().unwrap_or(0) {
+            return Err(invalid)
+        }
+
+        pending.retain(|seq_num,_| *seq_num > last_delivered);
+        if pending.is_empty() {
+            self.pending.remove(&client_id);
+        }
+        Ok(())
+    }
+
+    /// The payload the broker lost, if it is still pending. None if there is nothing to
+    /// submit again, e.g. because its inclusion was proved in the meantime.
+    pub fn resubmission(&self, resubmit: &Resubmit) -> Option<Payload> {
+        let bytes = self.pending.get(&resubmit.client_id)?.get(&resubmit.seq_num)?;
+        Payload::from_bytes(bytes).ok()
+    }
+
+    /// Records the payload as submitted and returns its serialized form,
+    /// which is exactly what has to be sent to the broker
+    pub fn submit(&mut self, payload: &Payload) -> Vec<u8> {
+        let bytes = payload.to_bytes();
+        self.pending
+            .entry(payload.num_id)
+            .or_default()
+            .insert(payload.seq_num, bytes.clone());
+        bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.values().map(|p| p.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks that `path` proves the inclusion of one of the pending payloads of
+    /// `client_id` under the root of `context`, at the position the path claims.
+    /// On success the payload is no longer pending and its sequence number is returned,
+    /// the position to sign for is `path.get_index()`.
+    pub fn check_inclusion(&mut self, client_id: NumericalIdentifier, path: &MerklePath, context: &SigningContext) -> Result<SequenceNumber,BrokerMisbehaviour> {
+        if context.broker_id != self.broker_id {
+            return Err(BrokerMisbehaviour::WrongBroker { client_id, broker_id: context.broker_id })
+        }
+
+        let invalid = BrokerMisbehaviour::InvalidInclusionProof { client_id, context: *context };
+        let pending = self.pending.get_mut(&client_id).ok_or(invalid.clone())?;
+
+        let included = pending
+            .iter()
+            .find(|(_,bytes)| path.verify(context.root, path.get_index(), bytes).is_ok())
+            .map(|(seq_num,_)| *seq_num);
+
+        match included {
+            Some(seq_num) => {
+                pending.remove(&seq_num);
+                if pending.is_empty() {
+                    self.pending.remove(&client_id);
+                }
+                Ok(seq_num)
+            },
+            None => Err(invalid),
+        }
+    }
+}
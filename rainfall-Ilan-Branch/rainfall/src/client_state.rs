@@ -0,0 +1,102 @@
+use core::fmt;
+use std::collections::HashMap;
+
+use crate::batch::{BatchId, NumericalIdentifier, PositionInBatch, SealedBatch};
+
+/// Where a client stands with respect to the batches of the broker.
+/// A client goes through the states in order and back to the first one:
+/// its payload is added to a batch in construction, the batch is sealed and the
+/// client is expected to sign its root, and once the batch is distilled
+/// the client can submit its next payload.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ClientState {
+    NotAssignedToBatch,
+    AssignedToBatch(BatchId,PositionInBatch),
+    WaitingForSignature(BatchId,PositionInBatch),
+}
+
+#[derive(Debug,PartialEq,Eq)]
+pub struct InvalidTransition;
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The client is not in a state that allows this transition")
+    }
+}
+
+/// The state of every known client, by numerical identifier, along with
+/// the clients each proposal is waiting on so that they can be released together.
+#[derive(Debug,Default)]
+pub struct ClientStates {
+    states: HashMap<NumericalIdentifier,ClientState>,
+    waiting: HashMap<BatchId,Vec<NumericalIdentifier>>,
+}
+
+impl ClientStates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking the client, which is not assigned to any batch yet.
+    /// Does nothing if the client is already known.
+    pub fn add(&mut self, client_id: NumericalIdentifier) {
+        self.states.entry(client_id).or_insert(ClientState::NotAssignedToBatch);
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// None if the client is unknown
+    pub fn get(&self, client_id: NumericalIdentifier) -> Option<ClientState> {
+        self.states.get(&client_id).copied()
+    }
+
+    /// The payload of the client was added at `pos` in the batch in construction
+    pub fn assign(&mut self, client_id: NumericalIdentifier, batch_id: BatchId, pos: PositionInBatch) -> Result<(),InvalidTransition> {
+        match self.states.get_mut(&client_id) {
+            Some(state @ ClientState::NotAssignedToBatch) => {
+                *state = ClientState::AssignedToBatch(batch_id,pos);
+                Ok(())
+            },
+            _ => Err(InvalidTransition),
+        }
+    }
+
+    /// The batch became a proposal: each of its clients now has to sign its root
+    pub fn wait_for_signature(&mut self, sealed: &SealedBatch) {
+        let batch_id = sealed.context.batch_id;
+        let mut waiting = Vec::with_capacity(sealed.client_ids.len());
+
+        for (pos,client_id) in sealed.client_ids.iter().enumerate() {
+            if let Some(state) = self.states.get_mut(client_id) {
+                if *state == ClientState::AssignedToBatch(batch_id,pos) {
+                    *state = ClientState::WaitingForSignature(batch_id,pos);
+                    waiting.push(*client_id);
+                }
+            }
+        }
+        self.waiting.insert(batch_id, waiting);
+    }
+
+    /// The batch was distilled: its clients can submit a new payload.
+    /// Returns the number of clients released.
+    pub fn release(&mut self, batch_id: BatchId) -> usize {
+        let waiting = self.waiting.remove(&batch_id).unwrap_or_default();
+        let mut released = 0;
+
+        for client_id in waiting {
+            if let Some(state) = self.states.get_mut(&client_id) {
+                if matches!(state, ClientState::WaitingForSignature(id,_) if *id == batch_id) {
+                    *state = ClientState::NotAssignedToBatch;
+                    released += 1;
+                }
+            }
+        }
+        released
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use crate::batch::BatchError;
+use crate::merkle::MerklePath;
+
+/// Every parameter of the batching pipeline. The broker, the clients and the
+/// tests all build their components from one of these, so that small batches can
+/// be used in tests and big ones in production without changing a constant.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct BatchConfig {
+    /// Number of payloads at which a batch in construction is sealed
+    pub batch_size: usize,
+    /// Number of batches in construction at the same time. New payloads are spread
+    /// over them in turn.
+    pub constructions: usize,
+    /// Maximum number of proposals waiting for signatures. Once it is reached,
+    /// full batches in construction wait before being sealed.
+    pub max_proposals: usize,
+    /// Maximum time a batch stays in construction after its first payload
+    pub seal_timeout: Duration,
+    /// Time the clients of a proposal have to sign its root once the proofs are sent
+    pub signature_timeout: Duration,
+    /// Fraction (between 0 and 1) of the clients of a proposal that have to sign
+    /// before it is distilled without waiting for the signature timeout
+    pub quorum_fraction: f64,
+    /// Number of messages sent or received by one call to sendmmsg/recvmmsg
+    pub vlen: usize,
+    /// Size in bytes of the buffer of each of those messages
+    pub bufsize: usize,
+}
+
+impl BatchConfig {
+    /// Checks every parameter before a `BatchManager` is built from the configuration
+    pub fn validate(&self) -> Result<(),BatchError> {
+        if self.batch_size == 0 {
+            return Err(BatchError::InvalidConfig("batch_size must be positive"))
+        }
+        if self.constructions == 0 {
+            return Err(BatchError::InvalidConfig("constructions must be positive"))
+        }
+        if self.max_proposals == 0 {
+            return Err(BatchError::InvalidConfig("max_proposals must be positive"))
+        }
+        if !(self.quorum_fraction > 0.0 && self.quorum_fraction <= 1.0) {
+            return Err(BatchError::InvalidConfig("quorum_fraction must be in (0, 1]"))
+        }
+        /*every proof of inclusion has to fit in the buffer of one message */
+        let depth = self.batch_size.next_power_of_two().trailing_zeros() as usize;
+        if depth > MerklePath::MAX_DEPTH || MerklePath::serialized_len(depth) > self.bufsize {
+            return Err(BatchError::InvalidConfig("bufsize can't hold a proof of inclusion of a batch of batch_size payloads"))
+        }
+        Ok(())
+    }
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 1<<16,
+            constructions: 1,
+            max_proposals: 4,
+            seal_timeout: Duration::from_millis(100),
+            signature_timeout: Duration::from_millis(500),
+            quorum_fraction: 1.0,
+            vlen: 1024,
+            bufsize: 1024,
+        }
+    }
+}
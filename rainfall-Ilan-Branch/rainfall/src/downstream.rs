@@ -0,0 +1,157 @@
+use core::fmt;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::batch::{BatchId, NumericalIdentifier, Payload, SequenceNumber};
+use crate::batch_log::{write_header, LogError, LoggedBatch, LOG_TAG, LOG_VERSION};
+use crate::registry::ClientRegistry;
+use crate::signing::BrokerId;
+
+/// Reasons for a downstream server to refuse a batch. None of its payloads are delivered.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum DeliveryError {
+    /// The batch was proposed by another broker than the one the server listens to
+    WrongBroker { batch_id: BatchId, broker_id: BrokerId },
+    /// A payload of the batch comes from a client the server does not know, e.g. one that
+    /// signed up after the registry of the server was loaded
+    UnknownClient { batch_id: BatchId, client_id: NumericalIdentifier },
+    /// The payloads are not the leaves of the Merkle tree whose root the clients signed
+    InvalidRoot(BatchId),
+    /// The aggregate signature or an exception does not verify
+    InvalidSignature(BatchId),
+    /// The batch was already delivered, or comes after a batch with a greater id.
+    /// The broker hands off its batches in the order of their ids.
+    OutOfOrder { batch_id: BatchId, last_delivered: BatchId },
+    /// A payload of the batch does not have a greater sequence number than the last payload
+    /// of its client that was delivered, e.g. an old payload put back in the batch as an
+    /// exception, since its signature does not bind it to a batch
+    StaleSequenceNumber { batch_id: BatchId, client_id: NumericalIdentifier, seq_num: SequenceNumber, last_delivered: SequenceNumber },
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeliveryError::WrongBroker { batch_id, broker_id } => write!(f, "batch {} comes from unexpected broker {}", batch_id, broker_id),
+            DeliveryError::UnknownClient { batch_id, client_id } => write!(f, "batch {} holds a payload of unknown client {}", batch_id, client_id),
+            DeliveryError::InvalidRoot(batch_id) => write!(f, "the payloads of batch {} do not match its root", batch_id),
+            DeliveryError::InvalidSignature(batch_id) => write!(f, "the signatures of batch {} do not verify", batch_id),
+            DeliveryError::OutOfOrder { batch_id, last_delivered } => write!(f, "batch {} arrives after batch {} was delivered", batch_id, last_delivered),
+            DeliveryError::StaleSequenceNumber { batch_id, client_id, seq_num, last_delivered } => {
+                write!(f, "batch {} holds sequence number {} of client {} but {} was already delivered", batch_id, seq_num, client_id, last_delivered)
+            },
+        }
+    }
+}
+
+/// The broker end of the connection to a downstream server. The distilled batches are
+/// sent in the format of the batch log, so that the server reads them with a `BatchLogReader`.
+#[derive(Debug)]
+pub struct BatchSender<W: Write = TcpStream> {
+    writer: W,
+}
+
+impl BatchSender {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self,LogError> {
+        Self::new(TcpStream::connect(addr)?)
+    }
+}
+
+impl<W: Write> BatchSender<W> {
+    /// Starts the stream with the header of the batch log
+    pub fn new(mut writer: W) -> Result<Self,LogError> {
+        write_header(&mut writer, LOG_TAG, LOG_VERSION)?;
+        Ok(Self { writer })
+    }
+
+    pub fn send(&mut self, batch: &LoggedBatch) -> Result<(),LogError> {
+        self.writer.write_all(&batch.to_record())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The role of a downstream server: it checks every batch the broker sends it and hands
+/// the delivered payloads to the application, batch after batch in the order they arrive
+/// (which is the order of the batch log of the broker), and within a batch in the order
+/// of their positions.
+pub struct Downstream<F: FnMut(BatchId, &Payload)> {
+    broker_id: BrokerId,
+    registry: ClientRegistry,
+    deliver: F,
+    /// Number of batches delivered so far
+    delivered: usize,
+    /// Id of the last batch delivered, the next one must have a greater id
+    last_delivered: Option<BatchId>,
+    /// Sequence number of the last payload delivered for each client, the next one must be greater
+    delivered_seq: HashMap<NumericalIdentifier,SequenceNumber>,
+}
+
+impl<F: FnMut(BatchId, &Payload)> Downstream<F> {
+    pub fn new(broker_id: BrokerId, registry: ClientRegistry, deliver: F) -> Self {
+        Self {
+            broker_id,
+            registry,
+            deliver,
+            delivered: 0,
+            last_delivered: None,
+            delivered_seq: HashMap::new(),
+        }
+    }
+
+    /// Replaces the registry the signatures are checked against, e.g. after new clients signed up
+    pub fn set_registry(&mut self, registry: ClientRegistry) {
+        self.registry = registry;
+    }
+
+    pub fn delivered(&self) -> usize {
+        self.delivered
+    }
+
+    pub fn last_delivered(&self) -> Option<BatchId> {
+        self.last_delivered
+    }
+
+    /// Checks the root and the signatures of the batch and hands its delivered payloads
+    /// to the application, unless a batch with the same or a greater id was delivered
+    /// before, or one of its payloads is not newer than the last one of its client.
+    /// Returns the number of payloads delivered.
+    pub fn receive(&mut self, batch: &LoggedBatch) -> Result<usize,DeliveryError> {
+        let batch_id = batch.context.batch_id;
+        if batch.context.broker_id != self.broker_id {
+            return Err(DeliveryError::WrongBroker { batch_id, broker_id: batch.context.broker_id })
+        }
+        if let Some(last_delivered) = self.last_delivered.filter(|last| batch_id <= *last) {
+            return Err(DeliveryError::OutOfOrder { batch_id, last_delivered })
+        }
+        if let Some(payload) = batch.payloads.iter().find(|p| self.registry.get_pk(p.num_id).is_err()) {
+            return Err(DeliveryError::UnknownClient { batch_id, client_id: payload.num_id })
+        }
+        if !batch.check_root() {
+            return Err(DeliveryError::InvalidRoot(batch_id))
+        }
+        if !batch.verify(&self.registry) {
+            return Err(DeliveryError::InvalidSignature(batch_id))
+        }
+
+        /*the sequence numbers are checked against the payloads before them in the batch too */
+        let payloads = batch.delivered_payloads();
+        let mut last_seq: HashMap<NumericalIdentifier,SequenceNumber> = HashMap::new();
+        for payload in &payloads {
+            let (client_id, seq_num) = (payload.num_id, payload.seq_num);
+            let last = last_seq.get(&client_id).or(self.delivered_seq.get(&client_id));
+            if let Some(last_delivered) = last.filter(|last| seq_num <= **last) {
+                return Err(DeliveryError::StaleSequenceNumber { batch_id, client_id, seq_num, last_delivered: *last_delivered })
+            }
+            last_seq.insert(client_id, seq_num);
+        }
+
+        for payload in &payloads {
+            (self.deliver)(batch_id, payload);
+        }
+        self.delivered_seq.extend(last_seq);
+        self.delivered += 1;
+        self.last_delivered = Some(batch_id);
+        Ok(payloads.len())
+    }
+}
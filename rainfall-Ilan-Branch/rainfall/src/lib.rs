@@ -1,6 +1,18 @@
 pub mod batch;
+pub mod batch_log;
+pub mod client;
+pub mod client_state;
+pub mod codec;
+pub mod config;
+pub mod downstream;
 pub mod merkle;
 pub mod signature_tree;
 pub mod recvmessage;
+pub mod recovery;
+pub mod registry;
+pub mod rejection;
+pub mod signing;
+pub mod signup;
+pub mod wal;
 #[cfg(test)]
 mod test;
\ No newline at end of file
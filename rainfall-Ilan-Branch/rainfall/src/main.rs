@@ -17,18 +17,30 @@ use merkle::{Directions, MerklePath, MerkleTree};
 use rand::{Rng,RngCore};
 use crate::signature_tree::SignatureTree;
 use crate::batch::BatchProposal;
+use crate::config::BatchConfig;
 use std::fs::File;
 use std::io::{Read, Write};
 
 mod batch;
+mod batch_log;
+mod client;
+mod client_state;
+mod codec;
+mod config;
+mod downstream;
 mod merkle;
 mod signature_tree;
 mod recvmessage;
+mod recovery;
+mod registry;
+mod rejection;
+mod signing;
+mod signup;
+mod wal;
 #[cfg(test)]
 mod test;
 
 const RATE_LIMITER_THRESHOLD: u32 = 100;
-const BATCH_SIZE: usize = 1<<16;
 
 
 /*buffers_pks is the file that all the brokers and servers have.
@@ -50,9 +62,10 @@ It contains the publics keys but not the associated private keys.
 // }
 
 fn get_pks() -> Vec<PublicKey> {
-    let mut pks: Vec<PublicKey> = Vec::with_capacity(2*BATCH_SIZE);
+    let num_keys = 2 * BatchConfig::default().batch_size;
+    let mut pks: Vec<PublicKey> = Vec::with_capacity(num_keys);
     let mut f = File::open("src/pks").expect("Unable to open file");
-    for i in 0..(2* BATCH_SIZE) {
+    for i in 0..num_keys {
         let mut buf = [0u8;48];
         f.read(&mut buf).expect("failed to read");
         match PublicKey::from_bytes(&buf) {
@@ -1,19 +1,28 @@
 use core::fmt;
-use std::{collections::VecDeque, path, time::SystemTime};
-use bincode::Error;
+use std::thread;
 use blake3::Hash;
-use libc::SELFMAG;
 use serde::{Serialize,Deserialize};
 
-use crate::batch::Payload;
+use crate::codec::Cursor;
+use crate::signing::SigningContext;
 
-const BATCH_SIZE: usize = 1<<16;
+/// First byte hashed with every leaf, with every pair of children of an internal node,
+/// and with the child of a node that has only one. They can't be confused: a payload made
+/// of two hashes does not hash to their parent, so a proof of inclusion can't be made for
+/// an internal node passed off as a payload.
+const LEAF_TAG: u8 = 0;
+const NODE_TAG: u8 = 1;
+const ONLY_CHILD_TAG: u8 = 2;
+
+/// Nodes of a level `new_parallel` gives a thread at the least, fewer are hashed faster
+/// than a thread is started
+const MIN_CHUNK_LEN: usize = 1 << 12;
 
 /// Directions will be useful for MerkleProof
 /// When we will reconstruct the root, we will need the 
 /// directions to know in which order to concatenate the hashes
 #[derive(Debug)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 #[derive(serde::Serialize)]
 #[derive(serde::Deserialize)]
 pub enum Directions {
@@ -53,79 +62,374 @@ pub struct MerkleTree {
     pub tree: Vec<Vec<Hash>>,
 }
 
-#[derive(Debug,Clone,Serialize,Deserialize)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ProofError {
+    /// The index is not the one of a leaf of a tree of this size
+    IndexOutOfRange { index: usize, leaves: usize },
+    /// The path is not the one of the claimed leaf: its length or its directions don't match the index
+    WrongPosition { index: usize },
+    /// The path does not lead to the expected root
+    RootMismatch,
+    /// The positions of a multiproof are missing or not increasing, or it does not come with
+    /// as many leaves or hashes as its positions need
+    Malformed,
+    /// The path is deeper than `MerklePath::MAX_DEPTH`, so it can't be serialized
+    TooDeep { depth: usize },
+    /// The buffer is too short for the serialized path
+    BufferTooSmall { needed: usize, len: usize },
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProofError::IndexOutOfRange { index, leaves } => write!(f, "leaf {} is out of a tree of {} leaves", index, leaves),
+            ProofError::WrongPosition { index } => write!(f, "the path is not the path of leaf {}", index),
+            ProofError::RootMismatch => write!(f, "the path does not lead to the expected root"),
+            ProofError::Malformed => write!(f, "the proof does not match its positions"),
+            ProofError::TooDeep { depth } => write!(f, "a path of {} levels is too deep to be serialized", depth),
+            ProofError::BufferTooSmall { needed, len } => write!(f, "the path needs {} bytes but the buffer has {}", needed, len),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NotAMerklePath;
+
+impl fmt::Display for NotAMerklePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expected a Merkle path but got something else")
+    }
+}
+
+#[derive(Debug)]
+pub struct NotAMultiProof;
+
+impl fmt::Display for NotAMultiProof {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expected a Merkle multiproof but got something else")
+    }
+}
+
+/// Proof of inclusion of the leaves at several positions of the same tree at once.
+/// It holds the hashes of the nodes that are needed to recompute the root and that can't
+/// be computed from the leaves themselves, level by level from the leaves up: a sibling
+/// shared by several leaves is only there once, and a sibling that is itself computed
+/// from the leaves is not there at all.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct MerkleMultiProof {
+    /// Positions of the leaves, strictly increasing
+    indices: Vec<usize>,
+    leaves: usize,
+    hashes: Vec<Hash>,
+}
+
+impl MerkleMultiProof {
+    pub fn get_indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    /// Number of leaves of the tree the proof comes from
+    pub fn get_leaves(&self) -> usize {
+        self.leaves
+    }
+
+    /// Number of hashes the proof carries, against one path per leaf for separate proofs
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Checks that `leaves`, given in the order of the positions of the proof, lead to `root`
+    pub fn verify(&self, root: Hash, leaves: &[&[u8]]) -> Result<(),ProofError> {
+        if self.indices.is_empty() || leaves.len() != self.indices.len() || self.indices.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(ProofError::Malformed)
+        }
+        if let Some(index) = self.indices.iter().find(|index| **index >= self.leaves) {
+            return Err(ProofError::IndexOutOfRange { index: *index, leaves: self.leaves })
+        }
+
+        /*The nodes known at the current level, by position. Two known siblings give their parent,
+        a known node without a known sibling takes the next hash of the proof as its sibling,
+        and the last node of a level with an odd number of nodes is hashed up alone */
+        let mut known: Vec<(usize,Hash)> = self.indices.iter().zip(leaves).map(|(i,leaf)| (*i, hash_leaf(leaf))).collect();
+        let mut hashes = self.hashes.iter();
+        let mut len = self.leaves;
+        while len > 1 {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut nodes = known.iter().peekable();
+            while let Some((idx,hash)) = nodes.next() {
+                let sibling = idx ^ 1;
+                let parent = match nodes.peek() {
+                    Some((next,next_hash)) if *next == sibling => {
+                        let parent = hash_concat(*hash, *next_hash);
+                        nodes.next();
+                        parent
+                    },
+                    _ if sibling >= len => hash_only_child(*hash),
+                    _ => {
+                        let sibling_hash = *hashes.next().ok_or(ProofError::Malformed)?;
+                        if idx.is_multiple_of(2) { hash_concat(*hash, sibling_hash) } else { hash_concat(sibling_hash, *hash) }
+                    },
+                };
+                parents.push((idx / 2, parent));
+            }
+            known = parents;
+            len = len.div_ceil(2);
+        }
+
+        if hashes.next().is_some() {
+            return Err(ProofError::Malformed)
+        }
+        match known[..] {
+            [(0,computed)] if computed == root => Ok(()),
+            _ => Err(ProofError::RootMismatch),
+        }
+    }
+
+    /*Layout: the number of leaves of the tree, the number of positions followed by each
+    position, and the number of hashes followed by each hash (32 bytes). Every integer 
+    takes 8 bytes, big endian. */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(24 + self.indices.len() * 8 + self.hashes.len() * 32);
+        buf.extend_from_slice(&(self.leaves as u64).to_be_bytes());
+        buf.extend_from_slice(&(self.indices.len() as u64).to_be_bytes());
+        for index in &self.indices {
+            buf.extend_from_slice(&(*index as u64).to_be_bytes());
+        }
+        buf.extend_from_slice(&(self.hashes.len() as u64).to_be_bytes());
+        for hash in &self.hashes {
+            buf.extend_from_slice(hash.as_bytes());
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self,NotAMultiProof> {
+        let mut cursor = Cursor::new(buf);
+        let leaves = cursor.take_u64().ok_or(NotAMultiProof)? as usize;
+
+        /*the counts are checked against what is left before anything is allocated */
+        let num_indices = cursor.take_u64().ok_or(NotAMultiProof)? as usize;
+        if num_indices > buf.len() / 8 {
+            return Err(NotAMultiProof)
+        }
+        let indices = (0..num_indices)
+            .map(|_| cursor.take_u64().map(|i| i as usize))
+            .collect::<Option<Vec<usize>>>()
+            .ok_or(NotAMultiProof)?;
+
+        let num_hashes = cursor.take_u64().ok_or(NotAMultiProof)? as usize;
+        if num_hashes > buf.len() / 32 {
+            return Err(NotAMultiProof)
+        }
+        let hashes = (0..num_hashes)
+            .map(|_| cursor.take(32).map(|h| Hash::from(<[u8;32]>::try_from(h).expect("slice incorrect length"))))
+            .collect::<Option<Vec<Hash>>>()
+            .ok_or(NotAMultiProof)?;
+
+        if !cursor.is_done() {
+            return Err(NotAMultiProof)
+        }
+        Ok(Self { indices, leaves, hashes })
+    }
+}
+
+/// Builds a `MerkleTree` one leaf at a time, as the payloads of a batch arrive.
+/// Each leaf is hashed when it is pushed and every pair of nodes is merged into its parent
+/// as soon as both are known, so that `finish` only has to complete the last node of each
+/// level. The tree is the same as the one `MerkleTree::new` builds from all the leaves.
+#[derive(Debug,Clone)]
+pub struct MerkleBuilder {
+    tree: Vec<Vec<Hash>>,
+}
+
+impl Default for MerkleBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MerkleBuilder {
+    pub fn new() -> Self {
+        Self { tree: vec![Vec::new()] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree[0].is_empty()
+    }
+
+    pub fn push(&mut self, leaf: &[u8]) {
+        self.tree[0].push(hash_leaf(leaf));
+
+        /*a node that completes a pair gives its parent, which may complete a pair in turn */
+        let mut level = 0;
+        while self.tree[level].len().is_multiple_of(2) {
+            let nodes = &self.tree[level];
+            let parent = hash_concat(nodes[nodes.len()-2], nodes[nodes.len()-1]);
+            if self.tree.len() == level + 1 {
+                self.tree.push(Vec::new());
+            }
+            self.tree[level+1].push(parent);
+            level += 1;
+        }
+    }
+
+    /*Only the parent of the last node of a level can be missing: it is the hash of the
+    last node alone if the level has an odd number of nodes, and of the last two otherwise.
+    Adding it may leave the level above with a missing parent in turn, up to the root. */
+    pub fn finish(mut self) -> MerkleTree {
+        let mut level = 0;
+        while self.tree[level].len() > 1 {
+            let nodes = &self.tree[level];
+            let complete = nodes.len().div_ceil(2);
+            let parent = if nodes.len().is_multiple_of(2) {
+                hash_concat(nodes[nodes.len()-2], nodes[nodes.len()-1])
+            } else {
+                hash_only_child(nodes[nodes.len()-1])
+            };
+
+            if self.tree.len() == level + 1 {
+                self.tree.push(Vec::new());
+            }
+            if self.tree[level+1].len() < complete {
+                self.tree[level+1].push(parent);
+            }
+            level += 1;
+        }
+        self.tree.truncate(level + 1);
+
+        MerkleTree { tree: self.tree }
+    }
+}
+
+/// Proof of inclusion of the leaf at `index` in a tree of `leaves` leaves: the hashes
+/// of the siblings along the way up to the root. The levels where the node has no
+/// sibling are skipped (see `MerkleTree::new`), so the index and the size of the tree
+/// tell which directions the path must have, and where the node is hashed up alone.
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
 pub struct MerklePath {
     pub path : Vec<(Hash,Directions)>,
+    index: usize,
+    leaves: usize,
 }
 
 impl MerklePath{
-    pub fn new(path: Vec<(Hash,Directions)>) -> Self {
+    pub fn new(path: Vec<(Hash,Directions)>, index: usize, leaves: usize) -> Self {
         Self {
             path,
+            index,
+            leaves,
         }   
     }
 
-    /*Explanatation for why the buffer is a slice [u8:514].
-    logbase2(65536) = 16. Each path will contain 16 hashes (hash of neighbours in merkle tree) and 
-    16 directions (encoded on one bit), which indicate in which order to concatenate the hashes.
-    Each hash is 32 bytes, so 16*32 = 512. The 16 directions can be encoded on two bytes. So 514 bytes.
-    We need one last byte to encode the len of the path. 
+    /// Position in the batch of the leaf the path proves
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    /// Number of leaves of the tree the path comes from
+    pub fn get_leaves(&self) -> usize {
+        self.leaves
+    }
+
+    /// Deepest path that can be encoded, enough for batches of up to 2^32 payloads
+    pub const MAX_DEPTH: usize = 32;
+
+    /// Number of bytes taken by a path of `depth` levels once serialized with `to_bytes`
+    pub fn serialized_len(depth: usize) -> usize {
+        1 + 8 + 8 + depth.div_ceil(8) + depth * 32 + SigningContext::SIZE + 8
+    }
+
+    /// Checks that the path is the one of the leaf at `index` and that it leads from `leaf` to `root`
+    pub fn verify(&self, root: Hash, index: usize, leaf: &[u8]) -> Result<(),ProofError> {
+        if index >= self.leaves {
+            return Err(ProofError::IndexOutOfRange { index, leaves: self.leaves })
+        }
+        if index != self.index || self.path.iter().map(|(_,d)| *d).ne(expected_directions(index, self.leaves)) {
+            return Err(ProofError::WrongPosition { index })
+        }
+        if recompute_root(self, leaf) != root {
+            return Err(ProofError::RootMismatch)
+        }
+        Ok(())
+    }
+
+    /*Layout of a serialized path of depth d:
+    one byte for d, the index of the leaf and the number of leaves of the tree (8 bytes each,
+    big endian), then ceil(d/8) bytes of directions (one bit per level, the first level
+    in the lowest bit of the first byte), then the d hashes of the neighbours (32 bytes each),
+    then the signing context (which contains the root of the batch the client has to sign)
+    and the 8 bytes of the client id.
+    A batch of 2^24 payloads gives paths of 24 levels, so 1 + 16 + 3 + 768 + 64 + 8 = 860 bytes.
     */
-    pub fn to_bytes(&self,buf: &mut [u8],client_id: u64){
-        assert!(buf.len() >= 515);
+    pub fn to_bytes(&self,buf: &mut [u8],context: &SigningContext,client_id: u64) -> Result<(),ProofError> {
+        let depth = self.path.len();
+        if depth > Self::MAX_DEPTH {
+            return Err(ProofError::TooDeep { depth })
+        }
+        if buf.len() < Self::serialized_len(depth) {
+            return Err(ProofError::BufferTooSmall { needed: Self::serialized_len(depth), len: buf.len() })
+        }
 
-        let path_len = self.path.len() as u8;
-    
-        let hashes: Vec<&Hash>= self.path
-            .iter()
-            .map(|(h,d)| h)
-            .collect();
-    
         let dirs: Vec<&Directions>= self.path
             .iter()
-            .map(|(h,d)| d)
+            .map(|(_,d)| d)
             .collect();
 
         let serialized_dirs: Vec<u8> = dirs.chunks(8)
-            .map(|c| c.to_vec())
-            .map(|vec| directions_to_byte(vec))
+            .map(directions_to_byte)
             .collect();
 
-        assert!(serialized_dirs.len() == 2);
+        buf[0] = depth as u8;
+        buf[1..9].copy_from_slice(&(self.index as u64).to_be_bytes());
+        buf[9..17].copy_from_slice(&(self.leaves as u64).to_be_bytes());
+        let mut start = 17;
+        buf[start..start+serialized_dirs.len()].copy_from_slice(&serialized_dirs);
+        start += serialized_dirs.len();
 
-        buf[0] = path_len;
-        buf[1] = serialized_dirs[0];
-        buf[2] = serialized_dirs[1];
-
-        let mut start = 3;
-        for hash in hashes {
+        for (hash,_) in self.path.iter() {
             buf[start..start+32].copy_from_slice(hash.as_bytes());
             start+=32;
         }
+        buf[start..start+SigningContext::SIZE].copy_from_slice(&context.to_bytes());
+        start+=SigningContext::SIZE;
         buf[start..start+8].copy_from_slice(&client_id.to_be_bytes());
+        Ok(())
     }
 
 
-    pub fn from_bytes(buf: &[u8]) ->  (Self,u64) {
-        assert!(!buf.is_empty());
+    /// Returns the path, the signing context of the batch it leads to and the client id.
+    /// Bytes after the client id are ignored, since the buffer of a message can be longer.
+    pub fn from_bytes(buf: &[u8]) -> Result<(Self,SigningContext,u64),NotAMerklePath> {
+        let mut cursor = Cursor::new(buf);
+        let depth = cursor.take(1).ok_or(NotAMerklePath)?[0] as usize;
+        if depth > Self::MAX_DEPTH {
+            return Err(NotAMerklePath)
+        }
+        let index = cursor.take_u64().ok_or(NotAMerklePath)? as usize;
+        let leaves = cursor.take_u64().ok_or(NotAMerklePath)? as usize;
 
-        let path_len = buf[0] as usize;
-        assert!(buf.len() >= 3 + path_len * 32);
-        
-        let mut path: Vec<(Hash,Directions)> = Vec::with_capacity(path_len);
-        
-        let mut directions = byte_to_direction(buf[1]);
-        directions.extend(byte_to_direction(buf[2]));
-        
-        let paths = &buf[3..];
-        for i in 0..path_len {
-            let bytes: [u8;32] = paths[i*32..(i+1)*32].try_into().expect("slice incorrect length");
-            let hash = blake3::Hash::from(bytes);
-            path.push((hash,directions[i]));
+        let directions: Vec<Directions> = cursor.take(depth.div_ceil(8))
+            .ok_or(NotAMerklePath)?
+            .iter()
+            .flat_map(|b| byte_to_direction(*b))
+            .collect();
+
+        let mut path: Vec<(Hash,Directions)> = Vec::with_capacity(depth);
+        for direction in directions.into_iter().take(depth) {
+            let bytes: [u8;32] = cursor.take(32).ok_or(NotAMerklePath)?.try_into().expect("slice incorrect length");
+            path.push((Hash::from(bytes),direction));
         }
-        
-        let client_id = u64::from_be_bytes(paths[(path_len * 32)..(path_len*32)+8].try_into().expect("slice incorrect length"));
-        (MerklePath { path }, client_id)
+
+        let context = SigningContext::from_bytes(cursor.take(SigningContext::SIZE).ok_or(NotAMerklePath)?).map_err(|_| NotAMerklePath)?;
+        let client_id = cursor.take_u64().ok_or(NotAMerklePath)?;
+        Ok((MerklePath { path, index, leaves }, context, client_id))
     }
 }
 
@@ -134,81 +438,201 @@ impl MerkleTree {
     
     /*TODO: need to check certain condition, removed the assert for 
     the placeholder value when changing states */
+    /*The last node of a level with an odd number of nodes is hashed up alone, with a tag of
+    its own, rather than moved up unchanged as in RFC 6962: that way no hash is found at two
+    levels of the tree, and the root commits to the height of the tree */
     pub fn new(leaves: &Vec<&[u8]>) -> Self{
+        let mut builder = MerkleBuilder::new();
+        for leaf in leaves {
+            builder.push(leaf);
+        }
+        builder.finish()
+    }
 
-        let hashed_leaves = leaves.iter().map(|x| blake3::hash(x)).collect();
-
-        let mut tree: Vec<Vec<Hash>> = Vec::new();
-        tree.push(hashed_leaves);
-
-        while tree.last().unwrap().len() > 1 {
-            let mut next_level = Vec::new();
-            let current_level = tree.last().unwrap();
+    /// Builds the same tree as `new` on `threads` threads. Each level, from the leaves up,
+    /// is split into chunks that are hashed on their own thread, parent by parent with
+    /// `hash_concat`, and put back side by side. There are never more threads than cores,
+    /// and a level too small to be worth a thread is hashed by the calling thread.
+    pub fn new_parallel(leaves: &[&[u8]], threads: usize) -> Self {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        let threads = threads.min(cores).max(1);
+        Self::from_chunks(leaves, leaves.len().div_ceil(threads).max(MIN_CHUNK_LEN))
+    }
 
-            for tuples in current_level.chunks(2) {
-                if tuples.len() == 2 {
-                    next_level.push(hash_concat(tuples[0], tuples[1]));
-                } else {
-                    next_level.push(tuples[0]);
-                }
-            }
-            tree.push(next_level);
+    /*One thread per chunk of `chunk_len` nodes of a level. The chunks have an even number
+    of nodes so that no pair of siblings is split, and only the last one can be odd. */
+    pub(crate) fn from_chunks(leaves: &[&[u8]], chunk_len: usize) -> Self {
+        let chunk_len = chunk_len.max(2).next_multiple_of(2);
+        let mut tree = vec![in_chunks(leaves, chunk_len, hash_leaves)];
+        while tree[tree.len()-1].len() > 1 {
+            let parents = in_chunks(&tree[tree.len()-1], chunk_len, hash_level);
+            tree.push(parents);
         }
 
-        Self {
-            tree,
-        }
+        MerkleTree { tree }
     }
 
     pub fn get_root_hash(&self) -> Hash {
         self.tree[self.tree.len()-1][0]
     }
 
-    pub fn find_merkle_path(&self,target_index: usize) -> MerklePath {
-        assert!(target_index < BATCH_SIZE);
+    pub fn find_merkle_path(&self,target_index: usize) -> Result<MerklePath,ProofError> {
+        if target_index >= self.tree[0].len() {
+            return Err(ProofError::IndexOutOfRange { index: target_index, leaves: self.tree[0].len() })
+        }
         let mut path: Vec<(Hash,Directions)> = Vec::new();
 
         let mut idx = target_index;
         for level in 0..self.tree.len()-1 {
             let sibling_index = idx^1;
-            if idx %2 == 0{
-                path.push((self.tree[level][sibling_index as usize].clone(),Directions::Left));
+            /*the last node of a level with an odd number of nodes has no sibling,
+            it is hashed up alone so there is nothing to add to the path */
+            if sibling_index >= self.tree[level].len() {
+                idx /= 2;
+                continue;
+            }
+            if idx.is_multiple_of(2) {
+                path.push((self.tree[level][sibling_index],Directions::Left));
             }else {
-                path.push((self.tree[level][sibling_index as usize].clone(),Directions::Right));
+                path.push((self.tree[level][sibling_index],Directions::Right));
             }
             idx /= 2;
         }
 
-        MerklePath::new(path)
+        Ok(MerklePath::new(path, target_index, self.tree[0].len()))
+    }
+
+    /// One proof for the leaves at all of `indices`, which are sorted and deduplicated.
+    /// Each sibling needed on the way up is in the proof once, unless it is computed from the leaves.
+    pub fn find_multiproof(&self, indices: &[usize]) -> Result<MerkleMultiProof,ProofError> {
+        let mut known: Vec<usize> = indices.to_vec();
+        known.sort_unstable();
+        known.dedup();
+        match known.last() {
+            None => return Err(ProofError::Malformed),
+            Some(index) if *index >= self.tree[0].len() => return Err(ProofError::IndexOutOfRange { index: *index, leaves: self.tree[0].len() }),
+            Some(_) => (),
+        }
+
+        let leaves = known.clone();
+        let mut hashes = Vec::new();
+        for level in 0..self.tree.len()-1 {
+            for (i,idx) in known.iter().enumerate() {
+                let sibling = idx ^ 1;
+                let sibling_known = known.get(i + 1) == Some(&sibling) || (i > 0 && known[i - 1] == sibling);
+                if sibling < self.tree[level].len() && !sibling_known {
+                    hashes.push(self.tree[level][sibling]);
+                }
+            }
+            known = known.iter().map(|idx| idx / 2).collect();
+            known.dedup();
+        }
+
+        Ok(MerkleMultiProof { indices: leaves, leaves: self.tree[0].len(), hashes })
     }
 }
 
-pub fn verify_merkle_proof(merklepath: MerklePath, payload_sent: &[u8]) -> Hash {
+/// Checks that `merklepath` proves the inclusion of `payload_sent` at `index` under `root`
+pub fn verify_merkle_proof(merklepath: &MerklePath, root: Hash, index: usize, payload_sent: &[u8]) -> Result<(),ProofError> {
+    merklepath.verify(root, index, payload_sent)
+}
 
-    let mut recomputed_root: Hash = blake3::hash(payload_sent);
-    for tuple in merklepath.path.iter(){
-        match tuple.1 {
-            Directions::Right => {
-                recomputed_root = hash_concat(tuple.0, recomputed_root);
-            },
-            Directions::Left => {
-                recomputed_root = hash_concat(recomputed_root, tuple.0);
-            },
+/*The directions of the path of the leaf at `index` in a tree of `leaves` leaves,
+from the leaf up, in the same way as `find_merkle_path` */
+fn expected_directions(index: usize, leaves: usize) -> impl Iterator<Item = Directions> {
+    let mut idx = index;
+    let mut len = leaves;
+    std::iter::from_fn(move || {
+        while len > 1 {
+            let has_sibling = (idx ^ 1) < len;
+            let direction = if idx.is_multiple_of(2) { Directions::Left } else { Directions::Right };
+            idx /= 2;
+            len = len.div_ceil(2);
+            if has_sibling {
+                return Some(direction)
+            }
         }
+        None
+    })
+}
+
+/*The levels where the node has no sibling are told by the index and the size of the
+tree, as in `expected_directions`: the node is hashed up alone there */
+fn recompute_root(merklepath: &MerklePath, payload_sent: &[u8]) -> Hash {
+    let mut recomputed_root: Hash = hash_leaf(payload_sent);
+    let mut siblings = merklepath.path.iter();
+    let mut idx = merklepath.index;
+    let mut len = merklepath.leaves;
+    while len > 1 {
+        let sibling = if (idx ^ 1) < len { siblings.next() } else { None };
+        recomputed_root = match sibling {
+            Some((hash,Directions::Right)) => hash_concat(*hash, recomputed_root),
+            Some((hash,Directions::Left)) => hash_concat(recomputed_root, *hash),
+            None => hash_only_child(recomputed_root),
+        };
+        idx /= 2;
+        len = len.div_ceil(2);
     }
-    recomputed_root 
+    recomputed_root
+}
+
+/// Hash of a leaf of the tree, what `verify_merkle_proof` starts from
+pub fn hash_leaf(leaf: &[u8]) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[LEAF_TAG]);
+    hasher.update(leaf);
+    hasher.finalize()
 }
 
 fn hash_concat(left_hash: Hash, right_hash: Hash) -> Hash{
     let mut hasher = blake3::Hasher::new(); 
+    hasher.update(&[NODE_TAG]);
     hasher.update(left_hash.as_bytes());
     hasher.update(right_hash.as_bytes());
     hasher.finalize()
 }
 
+/*Parent of a node that has no sibling, the last of a level with an odd number of nodes */
+fn hash_only_child(hash: Hash) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[ONLY_CHILD_TAG]);
+    hasher.update(hash.as_bytes());
+    hasher.finalize()
+}
+
+fn hash_leaves(leaves: &[&[u8]]) -> Vec<Hash> {
+    leaves.iter().map(|leaf| hash_leaf(leaf)).collect()
+}
+
+/*The parents of the nodes of a level, the last node being hashed up alone if there is an odd
+number of them */
+fn hash_level(nodes: &[Hash]) -> Vec<Hash> {
+    nodes
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_concat(*left, *right),
+            _ => hash_only_child(pair[0]),
+        })
+        .collect()
+}
+
+/*`hash` of every chunk of `chunk_len` items, each on its own thread, in the order of the chunks */
+fn in_chunks<T: Sync>(items: &[T], chunk_len: usize, hash: fn(&[T]) -> Vec<Hash>) -> Vec<Hash> {
+    if items.len() <= chunk_len {
+        return hash(items)
+    }
+    thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(chunk_len)
+            .map(|chunk| s.spawn(move || hash(chunk)))
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().expect("a thread building the tree panicked")).collect()
+    })
+}
+
 
-fn directions_to_byte(directions: Vec<&Directions>) -> u8 {
-    assert!(directions.len() == 8);
+fn directions_to_byte(directions: &[&Directions]) -> u8 {
+    assert!(directions.len() <= 8);
 
     let mut byte = 0u8;
     for (idx,dir) in directions.iter().enumerate() {
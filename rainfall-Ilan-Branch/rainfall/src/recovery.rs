@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use libc::sockaddr_in;
+
+use crate::batch::{BatchId, BatchManager, NumericalIdentifier, PositionInBatch, SealedBatch, Submission};
+use crate::batch_log::{BatchLogReader, LogError};
+use crate::config::BatchConfig;
+use crate::registry::ClientRegistry;
+use crate::rejection::Resubmit;
+use crate::signing::BrokerId;
+use crate::wal::{BatchWal, WalReader, WalRecord};
+
+/// The assignments of a batch read from the write-ahead log
+type Assignments = Vec<(PositionInBatch,NumericalIdentifier,sockaddr_in,Box<Submission>)>;
+
+/// What the broker starts from after `recover`
+#[derive(Debug)]
+pub struct Recovery {
+    /// Holds the proposals that were in flight, with their signature timeout started,
+    /// and fresh batches in construction
+    pub manager: BatchManager,
+    /// The proposals that were put back. Their clients are waiting for signature
+    /// and have to be sent their proofs of inclusion again.
+    pub proposals: Vec<SealedBatch>,
+    /// The clients whose payload was in a batch still in construction, which is lost.
+    /// Each of them has to be told to submit its payload again.
+    pub resubmits: Vec<(sockaddr_in,Resubmit)>,
+    /// The write-ahead log, rewritten with only the records of the proposals put back
+    pub wal: BatchWal,
+}
+
+/// Rebuilds the state of the broker after a restart from its batch log and its write-ahead log.
+///
+/// The batches in the batch log were delivered: the sequence numbers of their payloads are
+/// recorded in the registry so that they can't be replayed. The batches that the write-ahead
+/// log shows as sealed but that are not in the batch log are put back as proposals and
+/// returned in `proposals`, so that their clients are sent their proofs of inclusion again:
+/// the clients that had already signed before the restart sign again. The batches that were still in construction are dropped
+/// and their clients are listed in `resubmits`.
+///
+/// The registry must be freshly loaded: every client in it is expected to be not assigned.
+pub fn recover<P: AsRef<Path>, Q: AsRef<Path>>(broker_id: BrokerId, config: BatchConfig, registry: &mut ClientRegistry, wal_path: P, batch_log_path: Q) -> Result<Recovery,LogError> {
+    let mut manager = BatchManager::new(broker_id, config)?;
+
+    /*A record cut short by a crash ends the batch log. It is truncated to its last whole record,
+    otherwise every batch appended after the restart would be unreadable. A corrupted record is
+    not the trace of a crash: the batches after it would be lost, so recovery stops there */
+    let mut delivered = BTreeSet::new();
+    if batch_log_path.as_ref().exists() {
+        let mut reader = BatchLogReader::open(&batch_log_path)?;
+        let mut truncated = false;
+        for batch in reader.by_ref() {
+            match batch {
+                Ok(batch) => {
+                    registry.record_delivered(&batch.delivered_payloads());
+                    manager.skip_past(batch.context.batch_id);
+                    delivered.insert(batch.context.batch_id);
+                },
+                Err(e @ LogError::Truncated { .. }) => {
+                    eprintln!("batch log: {}, truncating it to its last whole record",e);
+                    truncated = true;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        if truncated {
+            OpenOptions::new().write(true).open(&batch_log_path)?.set_len(reader.valid_len())?;
+        }
+    }
+
+    let mut assigned: BTreeMap<BatchId,Assignments> = BTreeMap::new();
+    let mut sealed = BTreeSet::new();
+    if wal_path.as_ref().exists() {
+        for record in WalReader::open(&wal_path)? {
+            let record = match record {
+                Ok(record) => record,
+                Err(e @ LogError::Truncated { .. }) => {
+                    eprintln!("write-ahead log: {}, ignoring the rest of it",e);
+                    break;
+                },
+                Err(e) => return Err(e),
+            };
+            manager.skip_past(record.get_batch_id());
+            match record {
+                WalRecord::Assigned { batch_id, pos, client_id, addr, submission } => {
+                    assigned.entry(batch_id).or_default().push((pos, client_id, addr, submission));
+                },
+                WalRecord::Sealed { batch_id } => {
+                    sealed.insert(batch_id);
+                },
+            }
+        }
+    }
+
+    let mut proposals = Vec::new();
+    let mut resubmits = Vec::new();
+    let mut kept = Vec::new();
+    for (batch_id, mut entries) in assigned {
+        if delivered.contains(&batch_id) {
+            continue;
+        }
+        entries.sort_by_key(|(pos,_,_,_)| *pos);
+
+        /*a proposal can only be put back if we have the submission of every position */
+        let complete = entries.iter().enumerate().all(|(i,(pos,_,_,_))| i == *pos);
+        let pks: Option<Vec<_>> = entries.iter().map(|(_,client_id,_,_)| registry.get_pk(*client_id).ok()).collect();
+        let pks = match pks {
+            Some(pks) if complete && sealed.contains(&batch_id) => pks,
+            _ => {
+                resubmits.extend(entries.into_iter().map(|(_,client_id,addr,submission)| {
+                    (addr, Resubmit { client_id, seq_num: submission.payload.seq_num })
+                }));
+                continue;
+            },
+        };
+
+        let records: Vec<WalRecord> = entries.iter()
+            .map(|(pos,client_id,addr,submission)| WalRecord::Assigned {
+                batch_id,
+                pos: *pos,
+                client_id: *client_id,
+                addr: *addr,
+                submission: submission.clone(),
+            })
+            .collect();
+
+        let restored = entries.into_iter()
+            .zip(pks)
+            .map(|((_,client_id,addr,submission),pk)| (addr, client_id, *submission, pk))
+            .collect();
+        let sealed_batch = match manager.restore_proposal(batch_id, restored) {
+            Ok(sealed_batch) => sealed_batch,
+            Err(e) => {
+                eprintln!("batch {} could not be restored: {}",batch_id,e);
+                continue;
+            },
+        };
+        for (pos,client_id) in sealed_batch.client_ids.iter().enumerate() {
+            if let Err(e) = registry.assign(*client_id, batch_id, pos) {
+                eprintln!("batch {}: {}",batch_id,e);
+            }
+        }
+        registry.wait_for_signature(&sealed_batch);
+        kept.extend(records);
+        kept.push(WalRecord::Sealed { batch_id });
+        if manager.add_start_time(batch_id).is_ok() {
+            proposals.push(sealed_batch);
+        }
+    }
+
+    let wal = BatchWal::rewrite(&wal_path, &kept)?;
+    manager.add_batch();
+    Ok(Recovery { manager, proposals, resubmits, wal })
+}
@@ -1,19 +1,21 @@
 use libc::*;
 use std::{cmp, mem::{self, MaybeUninit}, slice};
 
-use crate::merkle::MerklePath;
-
-
-const VLEN:c_uint = 1024;
-const BUFSIZE: c_uint = 1024;
+use crate::config::BatchConfig;
+use crate::merkle::{MerklePath, ProofError};
+use crate::signing::SigningContext;
 
 
+/// `vlen` messages of `bufsize` bytes each, laid out for sendmmsg/recvmmsg.
+/// The buffer of message `i` starts at `bufs + i * bufsize`.
 #[derive(Clone,Debug)]
 pub struct RecvMessage { 
     pub msgs: *mut mmsghdr,
     pub iovecs: *mut iovec,
     pub addrs: *mut sockaddr_in,
-    pub bufs: *mut [u8;BUFSIZE as usize],
+    pub bufs: *mut u8,
+    vlen: usize,
+    bufsize: usize,
 }
 
 
@@ -21,70 +23,76 @@ unsafe impl Send for RecvMessage {}
 unsafe impl Sync for RecvMessage {}
 
 impl RecvMessage{
-    pub fn new() -> Self{
+    pub fn new(config: &BatchConfig) -> Self{
+        let vlen = config.vlen;
+        let bufsize = config.bufsize;
+        assert!(vlen > 0 && bufsize > 0);
+        /*posix_memalign only accepts powers of two as alignment */
+        let vlen_alignment = vlen.next_power_of_two();
+
         unsafe {
             let mut ret = 0;
 
             let mut memptr_msgs: *mut c_void = std::ptr::null_mut();
             let msgs_alignment: usize = std::mem::align_of::<mmsghdr>().next_power_of_two();
 
-            ret = posix_memalign(&mut memptr_msgs, msgs_alignment * VLEN as usize, std::mem::size_of::<mmsghdr>() * VLEN as usize);  
+            ret = posix_memalign(&mut memptr_msgs, msgs_alignment * vlen_alignment, std::mem::size_of::<mmsghdr>() * vlen);  
 
             if ret != 0 {
                 libc::free(memptr_msgs);                
                 panic!("posix_memalign");
             }   
             /*initializing memory */
-            std::ptr::write_bytes(memptr_msgs, 0, std::mem::size_of::<mmsghdr>() * VLEN as usize);
+            std::ptr::write_bytes(memptr_msgs, 0, std::mem::size_of::<mmsghdr>() * vlen);
 
             let mut memptr_iovecs: *mut c_void = std::ptr::null_mut();
             let iovecs_alignment = std::mem::align_of::<iovec>().next_power_of_two();
-            ret = posix_memalign(&mut memptr_iovecs, VLEN as usize * iovecs_alignment, std::mem::size_of::<iovec>() * VLEN as usize);
+            ret = posix_memalign(&mut memptr_iovecs, vlen_alignment * iovecs_alignment, std::mem::size_of::<iovec>() * vlen);
 
             if ret != 0{
                 libc::free(memptr_iovecs);
                 panic!("posix_memalign");
             };
             // /*initializing memory */
-            std::ptr::write_bytes(memptr_iovecs, 0, std::mem::size_of::<iovec>() * VLEN as usize);
+            std::ptr::write_bytes(memptr_iovecs, 0, std::mem::size_of::<iovec>() * vlen);
 
 
 
             let mut memptr_addrs: *mut c_void = std::ptr::null_mut();
             let addrs_alignment: usize = std::mem::align_of::<sockaddr_in>().next_power_of_two();
-            ret = posix_memalign(&mut memptr_addrs, VLEN as usize * addrs_alignment, std::mem::size_of::<sockaddr_in>() * VLEN as usize);  
+            ret = posix_memalign(&mut memptr_addrs, vlen_alignment * addrs_alignment, std::mem::size_of::<sockaddr_in>() * vlen);  
 
             if ret != 0{
                 libc::free(memptr_addrs);
                 panic!("posix_memalign");
             };
             /*initializing memory */
-            // libc::memset(memptr_addrs, 0, std::mem::size_of::<sockaddr_in>() * VLEN as usize);
-            std::ptr::write_bytes(memptr_addrs, 0, std::mem::size_of::<sockaddr_in>() * VLEN as usize);
+            // libc::memset(memptr_addrs, 0, std::mem::size_of::<sockaddr_in>() * vlen);
+            std::ptr::write_bytes(memptr_addrs, 0, std::mem::size_of::<sockaddr_in>() * vlen);
 
 
             let mut memptr_bufs = std::ptr::null_mut();
             assert!(mem::size_of::<usize>().is_power_of_two());
-            let bufs_alignment = cmp::max(mem::size_of::<usize>(), mem::size_of::<u8>() * VLEN as usize);
-            ret = posix_memalign(&mut memptr_bufs, bufs_alignment, mem::size_of::<u8>() * VLEN as usize * BUFSIZE as usize);  
+            let bufs_alignment = cmp::max(mem::size_of::<usize>(), mem::size_of::<u8>() * vlen_alignment);
+            ret = posix_memalign(&mut memptr_bufs, bufs_alignment, mem::size_of::<u8>() * vlen * bufsize);  
 
             if ret != 0{
                 libc::free(memptr_bufs);
                 panic!("posix_memalign");
             };
             // /*initializing memory */
-            // libc::memset(memptr_bufs, 0, std::mem::size_of::<u8>() * VLEN as usize * (BUFSIZE+1) as usize);
-            std::ptr::write_bytes(memptr_bufs, 0, std::mem::size_of::<u8>() * VLEN as usize * BUFSIZE as usize);
+            // libc::memset(memptr_bufs, 0, std::mem::size_of::<u8>() * vlen * (bufsize+1));
+            std::ptr::write_bytes(memptr_bufs, 0, std::mem::size_of::<u8>() * vlen * bufsize);
 
 
             assert!(!(memptr_iovecs as *mut iovec).is_null());
             assert!(!(memptr_msgs as *mut mmsghdr).is_null());
             assert!(!(memptr_addrs as *mut iovec).is_null());
-            assert!(!(memptr_bufs as *mut [u8;VLEN as usize]).is_null());
-            let iovec_slice = slice::from_raw_parts_mut(memptr_iovecs as *mut iovec, VLEN as usize);
-            let msgs_slice = slice::from_raw_parts_mut(memptr_msgs as *mut mmsghdr, VLEN as usize);
-            let addrs_slice = slice::from_raw_parts_mut(memptr_addrs as *mut sockaddr_in, VLEN as usize);
-            let bufs_slice = slice::from_raw_parts_mut(memptr_bufs as *mut [u8;BUFSIZE as usize], VLEN as usize);
+            assert!(!(memptr_bufs as *mut u8).is_null());
+            let iovec_slice = slice::from_raw_parts_mut(memptr_iovecs as *mut iovec, vlen);
+            let msgs_slice = slice::from_raw_parts_mut(memptr_msgs as *mut mmsghdr, vlen);
+            let addrs_slice = slice::from_raw_parts_mut(memptr_addrs as *mut sockaddr_in, vlen);
+            let bufs_slice = slice::from_raw_parts_mut(memptr_bufs as *mut u8, vlen * bufsize);
 
             let addr = sockaddr_in {
                 sin_family: libc::AF_INET as u16,
@@ -93,9 +101,9 @@ impl RecvMessage{
                 sin_zero:[0;8],
             };
 
-            for i in 0..VLEN as usize {
-                iovec_slice[i].iov_base = bufs_slice[i].as_mut_ptr() as *mut c_void;
-                iovec_slice[i].iov_len = BUFSIZE as usize;
+            for i in 0..vlen {
+                iovec_slice[i].iov_base = bufs_slice[i*bufsize..].as_mut_ptr() as *mut c_void;
+                iovec_slice[i].iov_len = bufsize;
                 msgs_slice[i].msg_hdr.msg_iov = &mut iovec_slice[i] as *mut _ as *mut iovec;
                 msgs_slice[i].msg_hdr.msg_iovlen = 1;
                 msgs_slice[i].msg_hdr.msg_name = &mut addrs_slice[i] as *mut _ as *mut c_void;
@@ -106,42 +114,64 @@ impl RecvMessage{
                 msgs: memptr_msgs as *mut mmsghdr,
                 iovecs : memptr_iovecs as *mut iovec,
                 addrs: memptr_addrs as *mut sockaddr_in,
-                bufs: memptr_bufs as *mut [u8;BUFSIZE as usize],
+                bufs: memptr_bufs as *mut u8,
+                vlen,
+                bufsize,
             }
         }
     }
 
+    pub fn get_vlen(&self) -> usize {
+        self.vlen
+    }
+
+    /// The buffer of the `i`-th message
+    pub fn get_buf(&self, i: usize) -> &[u8] {
+        assert!(i < self.vlen);
+        unsafe { slice::from_raw_parts(self.bufs.add(i * self.bufsize), self.bufsize) }
+    }
+
+    /// The address the `i`-th message was received from (or will be sent to)
+    pub fn get_addr(&self, i: usize) -> sockaddr_in {
+        assert!(i < self.vlen);
+        unsafe { *self.addrs.add(i) }
+    }
+
+    fn get_buf_mut(&mut self, i: usize) -> &mut [u8] {
+        assert!(i < self.vlen);
+        unsafe { slice::from_raw_parts_mut(self.bufs.add(i * self.bufsize), self.bufsize) }
+    }
+
 
-    pub fn fill(&mut self,addrs: &[sockaddr_in],paths: &[MerklePath],client_ids: &[u64]) {
+    pub fn fill(&mut self,addrs: &[sockaddr_in],paths: &[MerklePath],context: &SigningContext,client_ids: &[u64]) -> Result<(),ProofError> {
         unsafe {
 
             assert!(!addrs.is_empty() && !paths.is_empty() && addrs.len() == paths.len());
-            assert!(addrs.len() <= VLEN as usize);
+            assert!(addrs.len() <= self.vlen);
 
             let length = addrs.len();
             let addrs_slice = slice::from_raw_parts_mut(self.addrs, length as usize);
-            let bufs_slice = slice::from_raw_parts_mut(self.bufs,length as usize);
 
             for i in 0..length {
                 let p = &paths[i];
-                p.to_bytes(&mut bufs_slice[i][..BUFSIZE as usize],client_ids[i]);
+                p.to_bytes(self.get_buf_mut(i),context,client_ids[i])?;
                 addrs_slice[i] = addrs[i];
             }
         }
+        Ok(())
     }
 
 
     pub fn fill_to_send(&mut self,addr: sockaddr_in,bytes: &[Vec<u8>]) {
 
         unsafe {
-            assert!(!bytes.is_empty());
+            assert!(!bytes.is_empty() && bytes.len() <= self.vlen);
 
             let addrs_slice = slice::from_raw_parts_mut(self.addrs, bytes.len() as usize);
-            let bufs_slice = slice::from_raw_parts_mut(self.bufs,bytes.len() as usize);
 
             for i in 0..bytes.len() {
                 let b = &bytes[i][..];
-                bufs_slice[i][..b.len()].copy_from_slice(&b);
+                self.get_buf_mut(i)[..b.len()].copy_from_slice(&b);
                 addrs_slice[i] = addr;
             }
         }
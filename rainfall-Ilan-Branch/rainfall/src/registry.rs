@@ -0,0 +1,280 @@
+use core::fmt;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use blst::min_pk::PublicKey;
+
+use crate::batch::{BatchId, NumericalIdentifier, Payload, PositionInBatch, SealedBatch, SequenceNumber, Submission};
+use crate::client_state::{ClientState, ClientStates};
+use crate::signing::BrokerId;
+use crate::signup::SignUp;
+
+/// First bytes of every registry file
+const REGISTRY_TAG: &[u8;16] = b"RAINFALL_CLIENTS";
+const REGISTRY_VERSION: u8 = 1;
+/// tag (16 bytes) + version (1 byte) + number of clients (8 bytes)
+const HEADER_SIZE: usize = 25;
+/// numerical id (8 bytes) + compressed public key (48 bytes)
+const ENTRY_SIZE: usize = 56;
+
+#[derive(Debug)]
+pub enum RegistryError {
+    /// No client was registered with this id
+    UnknownClient(NumericalIdentifier),
+    /// Another key is already registered with this id
+    AlreadyRegistered(NumericalIdentifier),
+    /// The key is already registered with this other id
+    KeyAlreadyRegistered(NumericalIdentifier),
+    /// The client is known but not in a state that allows the transition
+    InvalidTransition(NumericalIdentifier),
+    /// The sign-up does not prove the possession of the secret key
+    InvalidProofOfPossession,
+    /// The submission is not signed by the client whose id it carries
+    UnauthenticatedSubmission(NumericalIdentifier),
+    /// A payload of the client with this sequence number or a later one was already delivered
+    StaleSequenceNumber { client_id: NumericalIdentifier, seq_num: SequenceNumber, last_delivered: SequenceNumber },
+    /// The client already has a payload in this batch, which is not delivered yet
+    PayloadInFlight { client_id: NumericalIdentifier, seq_num: SequenceNumber, batch_id: BatchId },
+    /// The file is not a registry, or is truncated
+    NotARegistry,
+    Io(io::Error),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::UnknownClient(client_id) => write!(f, "client {} is not registered", client_id),
+            RegistryError::AlreadyRegistered(client_id) => write!(f, "client {} is already registered with another key", client_id),
+            RegistryError::KeyAlreadyRegistered(client_id) => write!(f, "the key is already registered for client {}", client_id),
+            RegistryError::InvalidTransition(client_id) => write!(f, "client {} is not in a state that allows this transition", client_id),
+            RegistryError::InvalidProofOfPossession => write!(f, "the proof of possession of the sign-up is invalid"),
+            RegistryError::UnauthenticatedSubmission(client_id) => write!(f, "submission claiming to come from client {} is not signed by it", client_id),
+            RegistryError::StaleSequenceNumber { client_id, seq_num, last_delivered } => {
+                write!(f, "client {} submitted sequence number {} but {} was already delivered", client_id, seq_num, last_delivered)
+            },
+            RegistryError::PayloadInFlight { client_id, seq_num, batch_id } => {
+                write!(f, "client {} submitted sequence number {} while its payload in batch {} is not delivered", client_id, seq_num, batch_id)
+            },
+            RegistryError::NotARegistry => write!(f, "Expected a client registry but got something else"),
+            RegistryError::Io(e) => write!(f, "registry file: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for RegistryError {
+    fn from(e: io::Error) -> Self {
+        RegistryError::Io(e)
+    }
+}
+
+/// Every client the broker knows about: its public key and where it stands in
+/// the batching (see `ClientState`). Clients can be registered at any time under
+/// any numerical id, and packets from unregistered ids are rejected.
+#[derive(Debug,Default)]
+pub struct ClientRegistry {
+    pks: HashMap<NumericalIdentifier,PublicKey>,
+    /// Numerical id of each compressed public key
+    ids: HashMap<[u8;48],NumericalIdentifier>,
+    /// Highest sequence number delivered for each client that had a payload delivered
+    delivered: HashMap<NumericalIdentifier,SequenceNumber>,
+    states: ClientStates,
+    /// Id given to the next client that signs up, above every registered id
+    next_id: NumericalIdentifier,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the clients of a list of keys where the id of a client is
+    /// the index of its key, as in the key files the clients are generated with.
+    /// No proof of possession is checked: the keys must come from a trusted setup.
+    pub fn from_pks(pks: Vec<PublicKey>) -> Self {
+        let mut registry = Self::new();
+        for (client_id,pk) in pks.into_iter().enumerate() {
+            registry.insert(client_id as NumericalIdentifier, pk);
+        }
+        registry
+    }
+
+    /// Registering the same key twice is harmless, registering another key under a used id is not.
+    /// Neither is registering a used key under another id: both ids would sign with the same key.
+    pub fn register(&mut self, client_id: NumericalIdentifier, pk: PublicKey) -> Result<(),RegistryError> {
+        match self.pks.get(&client_id) {
+            Some(known) if *known == pk => Ok(()),
+            Some(_) => Err(RegistryError::AlreadyRegistered(client_id)),
+            None => {
+                if let Some(known_id) = self.ids.get(&pk.compress()) {
+                    return Err(RegistryError::KeyAlreadyRegistered(*known_id))
+                }
+                self.insert(client_id, pk);
+                Ok(())
+            },
+        }
+    }
+
+    /// Checks the proof of possession of the sign-up and gives the next free id to its key.
+    /// A key that is already registered keeps its id, so a client whose reply
+    /// got lost can simply sign up again.
+    pub fn sign_up(&mut self, signup: &SignUp) -> Result<NumericalIdentifier,RegistryError> {
+        if !signup.verify() {
+            return Err(RegistryError::InvalidProofOfPossession)
+        }
+        if let Some(client_id) = self.ids.get(&signup.pk.compress()) {
+            return Ok(*client_id)
+        }
+
+        let client_id = self.next_id;
+        self.insert(client_id, signup.pk);
+        Ok(client_id)
+    }
+
+    fn insert(&mut self, client_id: NumericalIdentifier, pk: PublicKey) {
+        self.pks.insert(client_id, pk);
+        self.ids.insert(pk.compress(), client_id);
+        self.states.add(client_id);
+        self.next_id = self.next_id.max(client_id.saturating_add(1));
+    }
+
+    pub fn len(&self) -> usize {
+        self.pks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pks.is_empty()
+    }
+
+    pub fn get_pk(&self, client_id: NumericalIdentifier) -> Result<PublicKey,RegistryError> {
+        self.pks.get(&client_id).copied().ok_or(RegistryError::UnknownClient(client_id))
+    }
+
+    /// Checks that the submission is signed by the client whose id it carries, and
+    /// returns the key of that client. Anything else must be dropped before it
+    /// reaches a batch, otherwise anyone could take the slot of another client.
+    pub fn authenticate(&self, broker_id: BrokerId, submission: &Submission) -> Result<PublicKey,RegistryError> {
+        let client_id = submission.payload.num_id;
+        let pk = self.get_pk(client_id)?;
+        if !submission.verify(broker_id, &pk) {
+            return Err(RegistryError::UnauthenticatedSubmission(client_id))
+        }
+        Ok(pk)
+    }
+
+    /// A payload is fresh if its sequence number is above the last one delivered for its client,
+    /// and if the client has no other payload in a batch that is not delivered yet
+    pub fn check_fresh(&self, payload: &Payload) -> Result<(),RegistryError> {
+        let (client_id, seq_num) = (payload.num_id, payload.seq_num);
+        if let Some(last_delivered) = self.delivered.get(&client_id).filter(|last| seq_num <= **last) {
+            return Err(RegistryError::StaleSequenceNumber { client_id, seq_num, last_delivered: *last_delivered })
+        }
+
+        match self.states.get(client_id) {
+            Some(ClientState::AssignedToBatch(batch_id,_)) | Some(ClientState::WaitingForSignature(batch_id,_)) => {
+                Err(RegistryError::PayloadInFlight { client_id, seq_num, batch_id })
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the payloads of a distilled batch as delivered: their sequence numbers can't be used again
+    pub fn record_delivered(&mut self, payloads: &[&Payload]) {
+        for payload in payloads {
+            let last = self.delivered.entry(payload.num_id).or_insert(payload.seq_num);
+            *last = (*last).max(payload.seq_num);
+        }
+    }
+
+    pub fn get_state(&self, client_id: NumericalIdentifier) -> Result<ClientState,RegistryError> {
+        self.states.get(client_id).ok_or(RegistryError::UnknownClient(client_id))
+    }
+
+    pub fn assign(&mut self, client_id: NumericalIdentifier, batch_id: BatchId, pos: PositionInBatch) -> Result<(),RegistryError> {
+        self.get_state(client_id)?;
+        self.states.assign(client_id, batch_id, pos).map_err(|_| RegistryError::InvalidTransition(client_id))
+    }
+
+    pub fn wait_for_signature(&mut self, sealed: &SealedBatch) {
+        self.states.wait_for_signature(sealed);
+    }
+
+    pub fn release(&mut self, batch_id: BatchId) -> usize {
+        self.states.release(batch_id)
+    }
+
+    /*Layout: the tag, the version, the number of clients (big endian) and then
+    for each client its id (big endian) followed by its compressed public key.
+    Clients are written in the order of their ids so that the file is deterministic. */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ids: Vec<&NumericalIdentifier> = self.pks.keys().collect();
+        ids.sort();
+
+        let mut buf = Vec::with_capacity(HEADER_SIZE + ids.len() * ENTRY_SIZE);
+        buf.extend_from_slice(REGISTRY_TAG);
+        buf.push(REGISTRY_VERSION);
+        buf.extend_from_slice(&(ids.len() as u64).to_be_bytes());
+        for client_id in ids {
+            buf.extend_from_slice(&client_id.to_be_bytes());
+            buf.extend_from_slice(&self.pks[client_id].compress());
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self,RegistryError> {
+        if buf.len() < HEADER_SIZE || &buf[..16] != REGISTRY_TAG || buf[16] != REGISTRY_VERSION {
+            return Err(RegistryError::NotARegistry)
+        }
+
+        /*Anything after the entries counted in the header was written by `append_client`
+        before it could update the count, and is ignored */
+        let count = u64::from_be_bytes(buf[17..25].try_into().expect("slice incorrect size")) as usize;
+        let len = count.checked_mul(ENTRY_SIZE).ok_or(RegistryError::NotARegistry)?;
+        let entries = buf[HEADER_SIZE..].get(..len).ok_or(RegistryError::NotARegistry)?;
+
+        let mut registry = Self::new();
+        for entry in entries.chunks(ENTRY_SIZE) {
+            let client_id = u64::from_be_bytes(entry[..8].try_into().expect("slice incorrect size"));
+            let pk = PublicKey::key_validate(&entry[8..]).map_err(|_| RegistryError::NotARegistry)?;
+            registry.register(client_id, pk)?;
+        }
+        Ok(registry)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self,RegistryError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(),RegistryError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Adds the entry of a client to a registry file, without rewriting the entries already
+    /// in it. The entry is on disk before the count of the header includes it, so a crash
+    /// or a concurrent `load` sees the file either with or without the new client.
+    pub fn append_client<P: AsRef<Path>>(&self, path: P, client_id: NumericalIdentifier) -> Result<(),RegistryError> {
+        let pk = self.get_pk(client_id)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut header = [0u8;HEADER_SIZE];
+        file.read_exact(&mut header).map_err(|_| RegistryError::NotARegistry)?;
+        if &header[..16] != REGISTRY_TAG || header[16] != REGISTRY_VERSION {
+            return Err(RegistryError::NotARegistry)
+        }
+        let count = u64::from_be_bytes(header[17..25].try_into().expect("slice incorrect size"));
+
+        let mut entry = Vec::with_capacity(ENTRY_SIZE);
+        entry.extend_from_slice(&client_id.to_be_bytes());
+        entry.extend_from_slice(&pk.compress());
+        file.seek(SeekFrom::Start(HEADER_SIZE as u64 + count * ENTRY_SIZE as u64))?;
+        file.write_all(&entry)?;
+        file.sync_data()?;
+
+        file.seek(SeekFrom::Start(17))?;
+        file.write_all(&(count + 1).to_be_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
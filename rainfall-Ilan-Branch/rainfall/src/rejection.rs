@@ -0,0 +1,189 @@
+use core::fmt;
+use blst::min_pk::{PublicKey, SecretKey, Signature};
+
+use crate::batch::{BatchError, BatchId, NumericalIdentifier, SequenceNumber};
+use crate::registry::RegistryError;
+use crate::signing::{sign_notice, verify_notice};
+
+/// Prefix of every rejection sent by the broker
+const REJECTION_TAG: &[u8;16] = b"RAINFALL_REJECT3";
+const STALE: u8 = 0;
+const IN_FLIGHT: u8 = 1;
+const PIPELINE_FULL: u8 = 2;
+/// Size of the signed part of a rejection, the signature of the broker comes after it
+const REJECTION_BODY: usize = 41;
+const SIGNATURE_SIZE: usize = 96;
+
+/// Prefix of every request to resubmit sent by the broker
+const RESUBMIT_TAG: &[u8;16] = b"RAINFALL_RESUBM2";
+/// Size of the signed part of a request to resubmit
+const RESUBMIT_BODY: usize = 32;
+
+/// Why a client does not act on a notice of the broker
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum NoticeError {
+    /// The message is not a notice of this kind, or is truncated
+    NotANotice,
+    /// The notice is not signed by the broker the client submits to: anyone can send
+    /// a client a packet, so it may be forged
+    InvalidSignature,
+}
+
+impl fmt::Display for NoticeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NoticeError::NotANotice => write!(f, "Expected a notice of the broker but got something else"),
+            NoticeError::InvalidSignature => write!(f, "the notice is not signed by the broker"),
+        }
+    }
+}
+
+/// Appends the signature of the broker over the body of a notice
+fn seal(mut buf: Vec<u8>, sk: &SecretKey) -> Vec<u8> {
+    let signature = sign_notice(sk, &buf);
+    buf.extend_from_slice(&signature.compress());
+    buf
+}
+
+/// Checks that the `body_len` first bytes of a notice are signed by the owner of `broker_pk`
+fn check_seal(buf: &[u8], body_len: usize, broker_pk: &PublicKey) -> Result<(),NoticeError> {
+    let signature = Signature::from_bytes(&buf[body_len..body_len+SIGNATURE_SIZE]).map_err(|_| NoticeError::InvalidSignature)?;
+    if !verify_notice(&buf[..body_len], &signature, broker_pk) {
+        return Err(NoticeError::InvalidSignature)
+    }
+    Ok(())
+}
+
+/// Why the broker dropped a submission
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RejectionReason {
+    /// The sequence number is not above `last_delivered`, the last one the broker delivered
+    /// for the client: the submission is a replay or a duplicate. The client has to submit its
+    /// next payload with a sequence number above `last_delivered`.
+    Stale { last_delivered: SequenceNumber },
+    /// A payload of the client is already in batch `batch_id`, which is not delivered yet.
+    /// The client has to wait for it before it submits another one.
+    InFlight { batch_id: BatchId },
+    /// Every batch in construction is full and waits for proposals to be distilled. The
+    /// client has to submit the same payload again later.
+    PipelineFull,
+}
+
+/// What the broker answers to a submission it drops because of its sequence number
+/// or because the client already has a payload in flight. It is signed by the broker.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Rejection {
+    pub client_id: NumericalIdentifier,
+    pub seq_num: SequenceNumber,
+    pub reason: RejectionReason,
+}
+
+impl Rejection {
+    /// tag (16 bytes) + client id (8 bytes) + rejected sequence number (8 bytes) + reason (1 byte)
+    /// + last delivered sequence number, id of the batch in flight or 0 (8 bytes)
+    /// + signature of the broker over all of the above (96 bytes)
+    pub const SIZE: usize = REJECTION_BODY + SIGNATURE_SIZE;
+
+    /// The rejection to send for an error of `ClientRegistry::check_fresh`
+    pub fn from_error(e: &RegistryError) -> Option<Self> {
+        match *e {
+            RegistryError::StaleSequenceNumber { client_id, seq_num, last_delivered } => {
+                Some(Self { client_id, seq_num, reason: RejectionReason::Stale { last_delivered } })
+            },
+            RegistryError::PayloadInFlight { client_id, seq_num, batch_id } => {
+                Some(Self { client_id, seq_num, reason: RejectionReason::InFlight { batch_id } })
+            },
+            _ => None,
+        }
+    }
+
+    /// The rejection to send for an error of `BatchManager::next_slot`
+    pub fn from_batch_error(client_id: NumericalIdentifier, seq_num: SequenceNumber, e: &BatchError) -> Option<Self> {
+        match e {
+            BatchError::PipelineFull => Some(Self { client_id, seq_num, reason: RejectionReason::PipelineFull }),
+            _ => None,
+        }
+    }
+
+    /// The rejection signed with the secret key of the broker
+    pub fn to_bytes(&self, sk: &SecretKey) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend_from_slice(REJECTION_TAG);
+        buf.extend_from_slice(&self.client_id.to_be_bytes());
+        buf.extend_from_slice(&self.seq_num.to_be_bytes());
+        match self.reason {
+            RejectionReason::Stale { last_delivered } => {
+                buf.push(STALE);
+                buf.extend_from_slice(&last_delivered.to_be_bytes());
+            },
+            RejectionReason::InFlight { batch_id } => {
+                buf.push(IN_FLIGHT);
+                buf.extend_from_slice(&(batch_id as u64).to_be_bytes());
+            },
+            RejectionReason::PipelineFull => {
+                buf.push(PIPELINE_FULL);
+                buf.extend_from_slice(&0u64.to_be_bytes());
+            },
+        }
+        seal(buf, sk)
+    }
+
+    /// The rejection, if it is signed by the owner of `broker_pk`
+    pub fn from_bytes(buf: &[u8], broker_pk: &PublicKey) -> Result<Self,NoticeError> {
+        if buf.len() < Self::SIZE || &buf[..16] != REJECTION_TAG {
+            return Err(NoticeError::NotANotice)
+        }
+
+        let value = u64::from_be_bytes(buf[33..41].try_into().expect("slice incorrect size"));
+        let reason = match buf[32] {
+            STALE => RejectionReason::Stale { last_delivered: value },
+            IN_FLIGHT => RejectionReason::InFlight { batch_id: value as BatchId },
+            PIPELINE_FULL if value == 0 => RejectionReason::PipelineFull,
+            _ => return Err(NoticeError::NotANotice),
+        };
+        check_seal(buf, REJECTION_BODY, broker_pk)?;
+        Ok(Self {
+            client_id: u64::from_be_bytes(buf[16..24].try_into().expect("slice incorrect size")),
+            seq_num: u64::from_be_bytes(buf[24..32].try_into().expect("slice incorrect size")),
+            reason,
+        })
+    }
+}
+
+/// What the broker sends after a restart to a client whose payload was in a batch
+/// still in construction: the batch is lost, and the client has to submit the payload
+/// again with the same sequence number. It is signed by the broker, otherwise anyone
+/// could make the client sign and send its pending payloads again.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Resubmit {
+    pub client_id: NumericalIdentifier,
+    pub seq_num: SequenceNumber,
+}
+
+impl Resubmit {
+    /// tag (16 bytes) + client id (8 bytes) + sequence number of the lost payload (8 bytes)
+    /// + signature of the broker over all of the above (96 bytes)
+    pub const SIZE: usize = RESUBMIT_BODY + SIGNATURE_SIZE;
+
+    /// The request signed with the secret key of the broker
+    pub fn to_bytes(&self, sk: &SecretKey) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend_from_slice(RESUBMIT_TAG);
+        buf.extend_from_slice(&self.client_id.to_be_bytes());
+        buf.extend_from_slice(&self.seq_num.to_be_bytes());
+        seal(buf, sk)
+    }
+
+    /// The request, if it is signed by the owner of `broker_pk`
+    pub fn from_bytes(buf: &[u8], broker_pk: &PublicKey) -> Result<Self,NoticeError> {
+        if buf.len() < Self::SIZE || &buf[..16] != RESUBMIT_TAG {
+            return Err(NoticeError::NotANotice)
+        }
+        check_seal(buf, RESUBMIT_BODY, broker_pk)?;
+
+        Ok(Self {
+            client_id: u64::from_be_bytes(buf[16..24].try_into().expect("slice incorrect size")),
+            seq_num: u64::from_be_bytes(buf[24..32].try_into().expect("slice incorrect size")),
+        })
+    }
+}
@@ -1,9 +1,8 @@
 use blst::min_pk::{AggregatePublicKey,AggregateSignature,Signature,PublicKey};
 use std::collections::VecDeque;
 
+use crate::signing::DST;
 
-const FAKE_ROOT: [u8;32] = [200, 117, 111, 57, 59, 197, 34, 95, 163, 98, 125, 151, 19, 45, 52, 158, 129, 137, 
-                            95, 68, 115, 72, 118, 235, 175, 93, 230, 204, 31, 175, 122, 223];
 
 #[derive(Debug)]
 struct NodeT {
@@ -49,11 +48,13 @@ impl SignatureTree{
         while tmp_sigs.len() > 1 {
             let len = tmp_sigs.len()/2;
             for i in 0..len {
-                let mut l1 = tmp_sigs.pop_front().unwrap();
+                let l1 = tmp_sigs.pop_front().unwrap();
                 let l2 = tmp_sigs.pop_front().unwrap();
-                l1.tuple.0.add_aggregate(&l2.tuple.0);
-                l1.tuple.1.add_aggregate(&l2.tuple.1);
-                tmp_sigs.push_back(NodeT::new(l1.tuple.0,l1.tuple.1,None,Some(l1),Some(l2)));
+                /*the children keep their own aggregates, only the parent gets the sum */
+                let (mut sig, mut pk) = l1.tuple;
+                sig.add_aggregate(&l2.tuple.0);
+                pk.add_aggregate(&l2.tuple.1);
+                tmp_sigs.push_back(NodeT::new(sig,pk,None,Some(l1),Some(l2)));
             }
         }
 
@@ -62,11 +63,15 @@ impl SignatureTree{
         }
     }
 
-    pub fn check(&self) -> Vec<usize> {
+    /// Verifies every signature of the tree against `message` and returns
+    /// the indices of the invalid ones
+    pub fn check(&self, message: &[u8]) -> Vec<usize> {
         
-        let dst = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_NUL_";
         let mut stack:Vec<&NodeT> = Vec::new();
-        let root = self.root.as_ref().unwrap();
+        let root = match self.root.as_ref() {
+            Some(root) => root,
+            None => return vec![],
+        };
         stack.push(root);
         let mut fake_index: Vec<usize> = Vec::new();
         
@@ -74,7 +79,7 @@ impl SignatureTree{
             let curr = stack.pop().expect("failed to unwrap");
             let sig = curr.tuple.0.to_signature();
             let pk = curr.tuple.1.to_public_key();
-            match sig.verify(true, &FAKE_ROOT, dst, &[], &pk, true) {
+            match sig.verify(true, message, DST, &[], &pk, true) {
                 blst::BLST_ERROR::BLST_SUCCESS => (),
                 blst::BLST_ERROR::BLST_VERIFY_FAIL => {
                     if curr.left_child.is_none() && curr.right_child.is_none() {
@@ -93,6 +98,14 @@ impl SignatureTree{
 }
 
 
+impl SignatureTree {
+    /// Aggregate of all the signatures of the tree, None if the tree is empty
+    pub fn aggregate_signature(&self) -> Option<Signature> {
+        self.root.as_ref().map(|root| root.tuple.0.to_signature())
+    }
+}
+
+
 /*For now useless  */
 
 fn aggregate_keys_fast(list_pks: &[&PublicKey],lower:usize,upper:usize) -> PublicKey{
@@ -0,0 +1,128 @@
+use core::fmt;
+use blake3::Hash;
+use blst::min_pk::{PublicKey, SecretKey, Signature};
+use blst::BLST_ERROR;
+
+use crate::batch::{BatchId, Payload};
+
+pub type BrokerId = u64;
+
+/// Domain separation tag given to blst for every signature of the protocol.
+/// Brokers, clients and servers must all use this one. It is the one of the proof of
+/// possession scheme of the BLS signature standard, the scheme under which the signatures
+/// of the roots can be checked with `fast_aggregate_verify` against the same message:
+/// every key they are aggregated with comes with a proof of possession (see `prove_possession`).
+pub const DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+/// Domain separation tag of the proofs of possession, as in the same scheme. It differs from
+/// `DST` so that a proof of possession can never be replayed as a signature of the protocol.
+pub const POP_DST: &[u8] = b"BLS_POP_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+/// Prefix of every signed batch root. It makes sure a signature over a root
+/// cannot be mistaken for a signature over any other message of the protocol.
+const ROOT_TAG: &[u8;16] = b"RAINFALL_ROOT_V1";
+
+/// Prefix of every individually signed payload
+const PAYLOAD_TAG: &[u8;16] = b"RAINFALL_PAYL_V1";
+
+/// What a client actually signs when it approves a batch: the root of the batch,
+/// bound to the broker that built it and to the id of the batch. A signature collected
+/// for one batch is therefore useless for any other batch, or for the same root proposed
+/// by another broker.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct SigningContext {
+    pub broker_id: BrokerId,
+    pub batch_id: BatchId,
+    pub root: Hash,
+}
+
+#[derive(Debug)]
+pub struct NotASigningContext;
+
+impl fmt::Display for NotASigningContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expected a signing context but got something else")
+    }
+}
+
+impl SigningContext {
+    /// tag (16 bytes) + broker id (8 bytes) + batch id (8 bytes) + root (32 bytes)
+    pub const SIZE: usize = 64;
+
+    pub fn new(broker_id: BrokerId, batch_id: BatchId, root: Hash) -> Self {
+        Self {
+            broker_id,
+            batch_id,
+            root,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8;Self::SIZE] {
+        let mut buf = [0u8;Self::SIZE];
+        buf[..16].copy_from_slice(ROOT_TAG);
+        buf[16..24].copy_from_slice(&self.broker_id.to_be_bytes());
+        buf[24..32].copy_from_slice(&(self.batch_id as u64).to_be_bytes());
+        buf[32..].copy_from_slice(self.root.as_bytes());
+        buf
+    }
+
+    /// Checks that `signature` is the signature of this context by the owner of `pk`
+    pub fn verify(&self, signature: &Signature, pk: &PublicKey) -> bool {
+        signature.verify(true, &self.to_bytes(), DST, &[], pk, true) == BLST_ERROR::BLST_SUCCESS
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self,NotASigningContext> {
+        if buf.len() < Self::SIZE || &buf[..16] != ROOT_TAG {
+            return Err(NotASigningContext)
+        }
+
+        let broker_id = u64::from_be_bytes(buf[16..24].try_into().expect("slice incorrect size"));
+        let batch_id = u64::from_be_bytes(buf[24..32].try_into().expect("slice incorrect size")) as BatchId;
+        let root_bytes: [u8;32] = buf[32..Self::SIZE].try_into().expect("slice incorrect size");
+
+        Ok(Self {
+            broker_id,
+            batch_id,
+            root: Hash::from(root_bytes),
+        })
+    }
+}
+
+/// What a client signs on its own when it submits a payload to `broker_id`.
+/// The broker keeps this signature and uses it in place of the aggregated one
+/// if the client does not sign the root of its batch in time.
+pub fn payload_message(broker_id: BrokerId, payload: &Payload) -> Vec<u8> {
+    let payload_bytes = payload.to_bytes();
+    let mut buf = Vec::with_capacity(PAYLOAD_TAG.len() + 8 + payload_bytes.len());
+    buf.extend_from_slice(PAYLOAD_TAG);
+    buf.extend_from_slice(&broker_id.to_be_bytes());
+    buf.extend_from_slice(&payload_bytes);
+    buf
+}
+
+pub fn verify_payload(broker_id: BrokerId, payload: &Payload, signature: &Signature, pk: &PublicKey) -> bool {
+    let message = payload_message(broker_id, payload);
+    signature.verify(true, &message, DST, &[], pk, true) == BLST_ERROR::BLST_SUCCESS
+}
+
+/// Signature of the client over its own public key. Checking it when the client signs up
+/// is what makes aggregating public keys naively safe: without it, a client could register
+/// a rogue key that cancels the keys of the others and forge aggregate signatures.
+pub fn prove_possession(sk: &SecretKey) -> Signature {
+    sk.sign(&sk.sk_to_pk().compress(), POP_DST, &[])
+}
+
+pub fn verify_possession(pk: &PublicKey, pop: &Signature) -> bool {
+    pop.verify(true, &pk.compress(), POP_DST, &[], pk, true) == BLST_ERROR::BLST_SUCCESS
+}
+
+/// Signature of the broker over a notice it sends to a client, such as a rejection. Notices
+/// start with their own tag, so the signature can't be taken for one over any other message.
+/// The client checks it against the public key of its broker before it acts on the notice.
+pub fn sign_notice(sk: &SecretKey, notice: &[u8]) -> Signature {
+    sk.sign(notice, DST, &[])
+}
+
+pub fn verify_notice(notice: &[u8], signature: &Signature, pk: &PublicKey) -> bool {
+    signature.verify(true, notice, DST, &[], pk, true) == BLST_ERROR::BLST_SUCCESS
+}
@@ -0,0 +1,94 @@
+use core::fmt;
+use blst::min_pk::{PublicKey, SecretKey, Signature};
+
+use crate::batch::NumericalIdentifier;
+use crate::signing::{prove_possession, verify_possession};
+
+/// Prefix of every sign-up request. It tells sign-ups apart from submissions,
+/// which start with the numerical id of an already registered client.
+const SIGNUP_TAG: &[u8;16] = b"RAINFALL_SIGNUP1";
+
+/// Prefix of the answer of the broker to a sign-up
+const SIGNUP_REPLY_TAG: &[u8;16] = b"RAINFALL_WELCOM1";
+
+#[derive(Debug)]
+pub struct NotASignUp;
+
+impl fmt::Display for NotASignUp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expected a sign-up message but got something else")
+    }
+}
+
+/// What a new client sends to join the broker: its public key along with
+/// a proof that it holds the matching secret key (see `prove_possession`).
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SignUp {
+    pub pk: PublicKey,
+    pub pop: Signature,
+}
+
+/// The numerical id the broker assigned to the public key of a new client.
+/// The client has to use it in every payload it submits.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SignUpReply {
+    pub client_id: NumericalIdentifier,
+    pub pk: PublicKey,
+}
+
+impl SignUp {
+    /// tag (16 bytes) + compressed public key (48 bytes) + compressed proof of possession (96 bytes)
+    pub const SIZE: usize = 160;
+
+    pub fn new(sk: &SecretKey) -> Self {
+        Self {
+            pk: sk.sk_to_pk(),
+            pop: prove_possession(sk),
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        verify_possession(&self.pk, &self.pop)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend_from_slice(SIGNUP_TAG);
+        buf.extend_from_slice(&self.pk.compress());
+        buf.extend_from_slice(&self.pop.compress());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self,NotASignUp> {
+        if buf.len() < Self::SIZE || &buf[..16] != SIGNUP_TAG {
+            return Err(NotASignUp)
+        }
+
+        let pk = PublicKey::key_validate(&buf[16..64]).map_err(|_| NotASignUp)?;
+        let pop = Signature::from_bytes(&buf[64..Self::SIZE]).map_err(|_| NotASignUp)?;
+        Ok(Self { pk, pop })
+    }
+}
+
+impl SignUpReply {
+    /// tag (16 bytes) + client id (8 bytes) + compressed public key (48 bytes)
+    pub const SIZE: usize = 72;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend_from_slice(SIGNUP_REPLY_TAG);
+        buf.extend_from_slice(&self.client_id.to_be_bytes());
+        buf.extend_from_slice(&self.pk.compress());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self,NotASignUp> {
+        if buf.len() < Self::SIZE || &buf[..16] != SIGNUP_REPLY_TAG {
+            return Err(NotASignUp)
+        }
+
+        let client_id = u64::from_be_bytes(buf[16..24].try_into().expect("slice incorrect size"));
+        let pk = PublicKey::from_bytes(&buf[24..Self::SIZE]).map_err(|_| NotASignUp)?;
+        Ok(Self { client_id, pk })
+    }
+}
@@ -1,6 +1,109 @@
 use crate::merkle::*;
+use crate::batch::{BatchError, BatchId, BatchManager, BatchProposal, BatchType, Payload, PositionInBatch, RootSignature, SealedBatch, Submission};
+use crate::batch_log::{write_header, BatchLog, BatchLogReader, LogError, LoggedBatch, LOG_TAG, LOG_VERSION};
+use crate::client::{BrokerMisbehaviour, PendingPayloads};
+use crate::client_state::{ClientState, ClientStates, InvalidTransition};
+use crate::config::BatchConfig;
+use crate::downstream::{BatchSender, DeliveryError, Downstream};
+use crate::recovery::recover;
+use crate::registry::{ClientRegistry, RegistryError};
+use crate::rejection::{NoticeError, Rejection, RejectionReason, Resubmit};
+use crate::signing::{BrokerId, SigningContext, DST};
+use crate::signup::{SignUp, SignUpReply};
+use crate::wal::{BatchWal, WalReader, WalRecord, COMPACT_AFTER};
 use std::{collections::VecDeque};
 use blake3::Hash;
+use blst::min_pk::{PublicKey, SecretKey};
+use libc::sockaddr_in;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Keys of the client seeded with `seed`, the same in every test
+pub(crate) fn key_pair(seed: u8) -> (SecretKey,PublicKey) {
+    let sk = SecretKey::key_gen(&[seed;32], &[]).unwrap();
+    let pk = sk.sk_to_pk();
+    (sk,pk)
+}
+
+/// Keys of the broker, which signs the notices it sends to the clients
+pub(crate) fn broker_key_pair() -> (SecretKey,PublicKey) {
+    key_pair(u8::MAX)
+}
+
+/// A broker with its batches in construction opened and a few clients, whose ids are their
+/// index in `keys`. The tests of the modules that handle batches start from there.
+pub(crate) struct Fixture {
+    pub manager: BatchManager,
+    pub keys: Vec<(SecretKey,PublicKey)>,
+    pub addr: sockaddr_in,
+}
+
+impl Fixture {
+    pub fn new(clients: u8, config: BatchConfig) -> Self {
+        Self::with_broker(0, clients, config)
+    }
+
+    pub fn with_broker(broker_id: BrokerId, clients: u8, config: BatchConfig) -> Self {
+        let mut manager = BatchManager::new(broker_id, config).unwrap();
+        manager.add_batch();
+        Self {
+            manager,
+            keys: (0..clients).map(key_pair).collect(),
+            addr: unsafe { mem::zeroed() },
+        }
+    }
+
+    /// A registry that knows every client of the fixture
+    pub fn registry(&self) -> ClientRegistry {
+        ClientRegistry::from_pks(self.keys.iter().map(|(_,pk)| *pk).collect())
+    }
+
+    /// The payload of the client, signed by it for the broker
+    pub fn submission(&self, client_id: u64, seq_num: u64, message: Vec<u8>) -> Submission {
+        let payload = Payload::new(client_id, seq_num, message);
+        Submission::sign(payload, &self.keys[client_id as usize].0, self.manager.get_broker_id())
+    }
+
+    pub fn submit(&mut self, client_id: u64, seq_num: u64, message: Vec<u8>) -> Result<(BatchId,PositionInBatch,Option<SealedBatch>),BatchError> {
+        let submission = self.submission(client_id, seq_num, message);
+        let pk = self.keys[client_id as usize].1;
+        self.manager.add_to_construction(self.addr, client_id, submission, pk)
+    }
+
+    /// The client at `pos` of the sealed batch signs its root
+    pub fn sign(&mut self, sealed: &SealedBatch, pos: usize) -> Result<bool,BatchError> {
+        let client_id = sealed.client_ids[pos] as usize;
+        let (sk,pk) = &self.keys[client_id];
+        let sig = sk.sign(&sealed.context.to_bytes(), DST, &[]);
+        self.manager.add_to_proposal(sealed.context.batch_id, pos, sig, *pk)
+    }
+}
+
+/// A path in the temporary directory that no other test uses, not even in another run of the
+/// tests: the name holds the id of the process and a counter. The file is removed on drop.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self(std::env::temp_dir().join(format!("rainfall_{}_{}_{}", name, std::process::id(), n)))
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -27,11 +130,12 @@ mod tests {
 
 
     #[test]
-    #[should_panic]
     fn test_no_inclustion() {
         let deque= vec![&[1,2],&[3,4],&[5,6],&[7,8],"hello".as_bytes()];
         let tree: MerkleTree = MerkleTree::new(&deque);
-        let path3 = tree.find_merkle_path(5);
+        assert_eq!(Err(ProofError::IndexOutOfRange { index: 5, leaves: 5 }), tree.find_merkle_path(5));
+        assert_eq!(Err(ProofError::IndexOutOfRange { index: 7, leaves: 5 }), tree.find_multiproof(&[7, 1]));
+        assert_eq!(Err(ProofError::Malformed), tree.find_multiproof(&[]));
     }
 
     #[test]
@@ -44,22 +148,1297 @@ mod tests {
         let leaf2 : &[u8] = &[3,4];
         let leaf3 : &[u8] = &[5,6];
 
-        let leaf1_hashed = blake3::hash(leaf1);   
-        let leaf2_hashed = blake3::hash(leaf2);     
-        let leaf3_hashed = blake3::hash(leaf3); 
+        /*leaves are hashed after a 0 byte, internal nodes after a 1 byte and a node without
+        a sibling after a 2 byte */
+        let leaf1_hashed = blake3::Hasher::new().update(&[0]).update(leaf1).finalize();   
+        let leaf2_hashed = blake3::Hasher::new().update(&[0]).update(leaf2).finalize();     
+        let leaf3_hashed = blake3::Hasher::new().update(&[0]).update(leaf3).finalize(); 
 
+        hasher.update(&[1]);
         hasher.update(leaf1_hashed.as_bytes());
         hasher.update(leaf2_hashed.as_bytes());
         let parent12 = hasher.finalize();
         hasher.reset();
 
+        let parent3 = blake3::Hasher::new().update(&[2]).update(leaf3_hashed.as_bytes()).finalize();
+        hasher.update(&[1]);
         hasher.update(parent12.as_bytes());
-        hasher.update(leaf3_hashed.as_bytes());
+        hasher.update(parent3.as_bytes());
         let root = hasher.finalize();
         
         let tree = MerkleTree::new(&vec);
 
         assert_eq!(root,tree.get_root_hash());
+        assert_eq!(parent3, tree.tree[1][1]);
+        assert_eq!(Ok(()), tree.find_merkle_path(2).unwrap().verify(root, 2, leaf3));
     
     }
+
+    #[test]
+    fn test_distilled_batch_keeps_stragglers() {
+        let mut f = Fixture::with_broker(7, 5, BatchConfig::default());
+        for client_id in 0..4 {
+            f.submit(client_id, 0, vec![client_id as u8;8]).unwrap();
+        }
+        /*client 4 submits a payload it did not sign */
+        let (sk,pk) = &f.keys[4];
+        let submission = Submission::new(Payload::new(4, 0, vec![4;8]), sk.sign(b"something else", DST, &[]));
+        f.manager.add_to_construction(f.addr, 4, submission, *pk).unwrap();
+
+        let sealed = f.manager.construction_to_proposal(0).unwrap();
+        f.manager.add_batch();
+        let context = sealed.context;
+        let other_batch = SigningContext::new(7, 1, context.root);
+
+        /*client 2 signs the root of another batch, clients 3 and 4 never sign */
+        f.sign(&sealed, 0).unwrap();
+        f.sign(&sealed, 1).unwrap();
+        let (sk,pk) = &f.keys[2];
+        let res = f.manager.add_to_proposal(0, 2, sk.sign(&other_batch.to_bytes(), DST, &[]), *pk);
+        assert_eq!(Err(BatchError::InvalidSignature { batch_id: 0, pos: 2 }), res);
+        f.manager.proposal_to_distilled(0).unwrap();
+
+        let distilled = match &f.manager.batches[&0] {
+            BatchType::DistilledBatch(distilled) => distilled,
+            _ => panic!("batch 0 should be distilled"),
+        };
+        assert_eq!(context, distilled.get_signing_context());
+        assert!(distilled.check().is_empty());
+        assert_eq!(vec![true,true,false,false,false], distilled.bitmap);
+
+        let positions: Vec<usize> = distilled.exceptions.iter().map(|e| e.position).collect();
+        assert_eq!(vec![2,3], positions);
+
+        let delivered: Vec<u64> = distilled.delivered_payloads().iter().map(|p| p.num_id).collect();
+        assert_eq!(vec![0,1,2,3], delivered);
+    }
+
+    #[test]
+    fn test_merkle_path_carries_signing_context() {
+        for size in [1usize, 2, 3, 5, 8, 13, 100, (1<<16) + 1] {
+            let payloads: Vec<[u8;8]> = (0..size as u64).map(|x| x.to_be_bytes()).collect();
+            let leaves: Vec<&[u8]> = payloads.iter().map(|x| &x[..]).collect();
+            let tree = MerkleTree::new(&leaves);
+            let root = tree.get_root_hash();
+            let context = SigningContext::new(1, 2, root);
+
+            for target in [0, size / 2, size - 1] {
+                let mut buf = [0u8;1024];
+                tree.find_merkle_path(target).unwrap().to_bytes(&mut buf, &context, 42).unwrap();
+                let (path, decoded_context, client_id) = MerklePath::from_bytes(&buf).unwrap();
+
+                assert_eq!(context, decoded_context);
+                assert_eq!(42, client_id);
+                assert_eq!(target, path.get_index());
+                assert_eq!(Ok(()), verify_merkle_proof(&path, root, target, &payloads[target]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_deep_merkle_path_round_trip() {
+        /*the path of a leaf in a batch of 2^24 payloads */
+        let path: Vec<(Hash,Directions)> = (0..24u8)
+            .map(|i| (blake3::hash(&[i]), if i % 3 == 0 { Directions::Right } else { Directions::Left }))
+            .collect();
+        let index = (0..24).filter(|i| i % 3 == 0).map(|i| 1 << i).sum();
+        let path = MerklePath::new(path, index, 1 << 24);
+        let context = SigningContext::new(1, 2, blake3::hash(b"root"));
+
+        let mut buf = vec![0u8;MerklePath::serialized_len(24)];
+        path.to_bytes(&mut buf, &context, 42).unwrap();
+        let (decoded, decoded_context, client_id) = MerklePath::from_bytes(&buf).unwrap();
+
+        assert_eq!(context, decoded_context);
+        assert_eq!(42, client_id);
+        assert_eq!(path, decoded);
+    }
+
+    #[test]
+    fn test_malformed_merkle_path_is_refused() {
+        let leaves: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i;8]).collect();
+        let slices: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
+        let tree = MerkleTree::new(&slices);
+        let context = SigningContext::new(1, 2, tree.get_root_hash());
+        let path = tree.find_merkle_path(1).unwrap();
+        let len = MerklePath::serialized_len(path.path.len());
+        let mut buf = vec![0u8;len];
+        path.to_bytes(&mut buf, &context, 42).unwrap();
+        assert_eq!(Err(ProofError::BufferTooSmall { needed: len, len: len - 1 }), path.to_bytes(&mut buf[1..], &context, 42));
+        let too_deep = MerklePath::new(vec![(tree.get_root_hash(),Directions::Left); MerklePath::MAX_DEPTH + 1], 0, 1);
+        assert_eq!(Err(ProofError::TooDeep { depth: MerklePath::MAX_DEPTH + 1 }), too_deep.to_bytes(&mut buf, &context, 42));
+
+        /*every truncation of a valid path, down to the empty buffer */
+        for end in 0..len {
+            assert!(MerklePath::from_bytes(&buf[..end]).is_err(), "{} bytes out of {}", end, len);
+        }
+
+        /*a depth beyond the deepest path, even with the bytes it would need */
+        let mut deep = vec![0u8;MerklePath::serialized_len(MerklePath::MAX_DEPTH + 1)];
+        deep[0] = MerklePath::MAX_DEPTH as u8 + 1;
+        assert!(MerklePath::from_bytes(&deep).is_err());
+        let mut oversized = buf.clone();
+        oversized[0] = u8::MAX;
+        assert!(MerklePath::from_bytes(&oversized).is_err());
+
+        /*garbage, and a path whose signing context lost its tag */
+        assert!(MerklePath::from_bytes(&[0xff;1024]).is_err());
+        let mut no_context = buf.clone();
+        no_context[len - 8 - SigningContext::SIZE] ^= 1;
+        assert!(MerklePath::from_bytes(&no_context).is_err());
+
+        /*whatever follows the client id is left alone */
+        buf.extend_from_slice(&[0xff;16]);
+        assert_eq!((path, context, 42), MerklePath::from_bytes(&buf).unwrap());
+    }
+
+    #[test]
+    fn test_client_refuses_invalid_inclusion_proof() {
+        let mut pending = PendingPayloads::new(1);
+        let payloads: Vec<Payload> = (0..4).map(|x| Payload::new(x, 0, vec![x as u8;8])).collect();
+        let bytes: Vec<Vec<u8>> = payloads.iter().map(|p| pending.submit(p)).collect();
+        let leaves: Vec<&[u8]> = bytes.iter().map(|x| &x[..]).collect();
+        let tree = MerkleTree::new(&leaves);
+        let context = SigningContext::new(1, 0, tree.get_root_hash());
+
+        /*the path of client 1 does not prove anything about the payload of client 0 */
+        let res = pending.check_inclusion(0, &tree.find_merkle_path(1).unwrap(), &context);
+        assert_eq!(Err(BrokerMisbehaviour::InvalidInclusionProof { client_id: 0, context }), res);
+
+        let other_broker = SigningContext::new(2, 0, tree.get_root_hash());
+        let res = pending.check_inclusion(0, &tree.find_merkle_path(0).unwrap(), &other_broker);
+        assert_eq!(Err(BrokerMisbehaviour::WrongBroker { client_id: 0, broker_id: 2 }), res);
+
+        assert_eq!(Ok(0), pending.check_inclusion(0, &tree.find_merkle_path(0).unwrap(), &context));
+        assert_eq!(3, pending.len());
+
+        /*once included, the payload is not pending anymore */
+        assert!(pending.check_inclusion(0, &tree.find_merkle_path(0).unwrap(), &context).is_err());
+    }
+
+    #[test]
+    fn test_partial_batch_sealed_by_timeout() {
+        assert!(BatchManager::new(0, BatchConfig::default()).unwrap().poll_seal(SystemTime::now()).is_empty());
+
+        let mut f = Fixture::new(3, BatchConfig::default());
+        /*an empty batch is never sealed */
+        assert!(f.manager.poll_seal(SystemTime::now() + Duration::from_secs(10)).is_empty());
+
+        for client_id in 0..3u64 {
+            let (batch_id, pos, sealed) = f.submit(client_id, 0, vec![client_id as u8]).unwrap();
+            assert_eq!((0, client_id as usize), (batch_id, pos));
+            assert!(sealed.is_none());
+        }
+
+        assert!(f.manager.poll_seal(SystemTime::now()).is_empty());
+        let sealed = f.manager.poll_seal(SystemTime::now() + Duration::from_secs(1)).pop().expect("batch should be sealed");
+        assert_eq!(0, sealed.context.batch_id);
+        assert_eq!(vec![0,1,2], sealed.client_ids);
+        assert!(matches!(f.manager.batches[&0], BatchType::Proposal(_)));
+        assert!(matches!(f.manager.batches[&1], BatchType::Construction(_)));
+    }
+
+    #[test]
+    fn test_proposal_distilled_when_timeout_expires() {
+        let mut f = Fixture::new(1, BatchConfig::default());
+        f.submit(0, 0, vec![0]).unwrap();
+        let sealed = f.manager.poll_seal(SystemTime::now() + Duration::from_secs(1)).pop().expect("batch should be sealed");
+
+        /*the timeout only starts once the proofs are sent */
+        assert!(f.manager.poll_expired(SystemTime::now() + Duration::from_secs(10)).is_empty());
+        f.manager.add_start_time(sealed.context.batch_id).unwrap();
+        assert!(f.manager.poll_expired(SystemTime::now()).is_empty());
+
+        assert_eq!(vec![0], f.manager.poll_expired(SystemTime::now() + Duration::from_secs(1)));
+        let distilled = f.manager.get_distilled(0).expect("batch should be distilled");
+        assert_eq!(vec![false], distilled.bitmap);
+        assert_eq!(1, distilled.exceptions.len());
+        assert!(f.manager.poll_expired(SystemTime::now() + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_proposal_distilled_early_on_quorum() {
+        for (quorum_fraction, signatures_needed) in [(1.0, 4), (0.5, 2)] {
+            let mut f = Fixture::new(4, BatchConfig { quorum_fraction, ..BatchConfig::default() });
+            for client_id in 0..4 {
+                f.submit(client_id, 0, vec![0]).unwrap();
+            }
+            let sealed = f.manager.poll_seal(SystemTime::now() + Duration::from_secs(1)).pop().expect("batch should be sealed");
+            f.manager.add_start_time(0).unwrap();
+
+            for pos in 0..signatures_needed {
+                let distilled = f.sign(&sealed, pos).unwrap();
+                assert_eq!(pos + 1 == signatures_needed, distilled);
+                if !distilled {
+                    /*a duplicate signature does not count towards the quorum */
+                    assert_eq!(Err(BatchError::DuplicateSignature { batch_id: 0, pos }), f.sign(&sealed, pos));
+                }
+            }
+
+            let distilled = f.manager.get_distilled(0).expect("batch should be distilled before the timeout");
+            assert_eq!(signatures_needed, distilled.bitmap.iter().filter(|b| **b).count());
+            assert_eq!(4 - signatures_needed, distilled.exceptions.len());
+        }
+    }
+
+    #[test]
+    fn test_invalid_signature_does_not_take_the_position() {
+        let mut f = Fixture::new(2, BatchConfig::default());
+        f.submit(0, 0, vec![0]).unwrap();
+        f.submit(1, 0, vec![1]).unwrap();
+        let sealed = f.manager.construction_to_proposal(0).unwrap();
+        f.manager.add_batch();
+
+        /*client 1 claims the id of client 0, with its own key or with the key of client 0 */
+        let (sk,_) = &f.keys[1];
+        let forged = sk.sign(&sealed.context.to_bytes(), DST, &[]);
+        let res = f.manager.add_to_proposal(0, 0, forged, f.keys[0].1);
+        assert_eq!(Err(BatchError::InvalidSignature { batch_id: 0, pos: 0 }), res);
+        let garbage = sk.sign(b"garbage", DST, &[]);
+        let res = f.manager.add_to_proposal(0, 0, garbage, f.keys[0].1);
+        assert_eq!(Err(BatchError::InvalidSignature { batch_id: 0, pos: 0 }), res);
+        assert!(matches!(&f.manager.batches[&0], BatchType::Proposal(proposal) if proposal.bitmap == vec![false,false]));
+
+        /*the genuine client can still sign, and its signature is the one that counts */
+        assert_eq!(Ok(false), f.sign(&sealed, 0));
+        assert_eq!(Err(BatchError::DuplicateSignature { batch_id: 0, pos: 0 }), f.sign(&sealed, 0));
+        f.manager.proposal_to_distilled(0).unwrap();
+        let distilled = f.manager.get_distilled(0).expect("batch should be distilled");
+        assert_eq!(vec![true,false], distilled.bitmap);
+        assert!(distilled.check().is_empty());
+    }
+
+    #[test]
+    fn test_small_batch_sealed_on_size() {
+        let mut f = Fixture::new(5, BatchConfig { batch_size: 2, ..BatchConfig::default() });
+
+        let mut sealed = vec![];
+        for client_id in 0..5u64 {
+            let (batch_id, pos, s) = f.submit(client_id, 0, vec![client_id as u8]).unwrap();
+            assert_eq!((client_id as usize / 2, client_id as usize % 2), (batch_id, pos));
+            sealed.extend(s);
+        }
+
+        assert_eq!(vec![0,1], sealed.iter().map(|s| s.context.batch_id).collect::<Vec<_>>());
+        assert_eq!(vec![2,3], sealed[1].client_ids);
+        assert!(matches!(f.manager.batches[&2], BatchType::Construction(_)));
+    }
+
+    #[test]
+    fn test_batch_manager_rejects_malformed_requests() {
+        let mut manager = BatchManager::new(0, BatchConfig::default()).unwrap();
+        let addr: sockaddr_in = unsafe { mem::zeroed() };
+        let (sk,pk) = key_pair(0);
+        let sig = sk.sign(b"anything", DST, &[]);
+
+        let submission = Submission::sign(Payload::new(0, 0, vec![0]), &sk, 0);
+        assert_eq!(Err(BatchError::UnknownBatch(0)), manager.add_to_construction(addr, 0, submission.clone(), pk).map(|_| ()));
+
+        manager.add_batch();
+        /*an empty batch has no root to propose */
+        assert_eq!(Err(BatchError::EmptyBatch(0)), manager.construction_to_proposal(0).map(|_| ()));
+        assert!(matches!(manager.batches[&0], BatchType::Construction(_)));
+        assert_eq!(Err(BatchError::EmptyBatch(5)), manager.restore_proposal(5, vec![]).map(|_| ()));
+        assert!(!manager.batches.contains_key(&5));
+
+        manager.add_to_construction(addr, 0, submission, pk).unwrap();
+        assert_eq!(Err(BatchError::UnknownBatch(1)), manager.add_to_proposal(1, 0, sig, pk));
+        assert_eq!(Err(BatchError::WrongState(0)), manager.add_to_proposal(0, 0, sig, pk));
+        assert_eq!(Err(BatchError::WrongState(0)), manager.proposal_to_distilled(0));
+
+        let sealed = manager.poll_seal(SystemTime::now() + Duration::from_secs(1)).pop().expect("batch should be sealed");
+        assert_eq!(Err(BatchError::WrongState(0)), manager.construction_to_proposal(0).map(|_| ()));
+        manager.add_start_time(sealed.context.batch_id).unwrap();
+        assert_eq!(Err(BatchError::WrongState(0)), manager.add_start_time(0));
+        assert_eq!(Err(BatchError::PositionOutOfRange { batch_id: 0, pos: 1 }), manager.add_to_proposal(0, 1, sig, pk));
+        assert!(matches!(&manager.batches[&0], BatchType::Proposal(proposal) if proposal.bitmap == vec![false]));
+    }
+
+    #[test]
+    fn test_invalid_configs_and_proposals_are_refused() {
+        let invalid = [
+            BatchConfig { batch_size: 0, ..BatchConfig::default() },
+            BatchConfig { constructions: 0, ..BatchConfig::default() },
+            BatchConfig { max_proposals: 0, ..BatchConfig::default() },
+            BatchConfig { quorum_fraction: 0.0, ..BatchConfig::default() },
+            BatchConfig { quorum_fraction: 1.5, ..BatchConfig::default() },
+            BatchConfig { quorum_fraction: f64::NAN, ..BatchConfig::default() },
+            BatchConfig { bufsize: 64, ..BatchConfig::default() },
+        ];
+        for config in invalid {
+            assert!(matches!(config.validate(), Err(BatchError::InvalidConfig(_))), "{:?}", config);
+            assert!(matches!(BatchManager::new(0, config), Err(BatchError::InvalidConfig(_))), "{:?}", config);
+        }
+        assert_eq!(Ok(()), BatchConfig::default().validate());
+
+        /*a proposal whose tree does not have a leaf per submission is refused */
+        let (sk,pk) = key_pair(0);
+        let submissions = vec![Submission::sign(Payload::new(0, 0, vec![0]), &sk, 0)];
+        let tree = MerkleTree::new(&vec![&[0u8][..], &[1u8][..]]);
+        let res = BatchProposal::with_tree(submissions.clone(), vec![pk], tree, 3, 0, Duration::from_secs(1));
+        assert_eq!(Some(BatchError::MismatchedLengths(3)), res.err());
+        let res = BatchProposal::new(submissions, vec![], 4, 0, Duration::from_secs(1));
+        assert_eq!(Some(BatchError::MismatchedLengths(4)), res.err());
+    }
+
+    #[test]
+    fn test_distilled_batches_are_evicted_once_drained() {
+        let mut f = Fixture::new(2, BatchConfig { batch_size: 2, ..BatchConfig::default() });
+
+        for round in 0..50u64 {
+            for client_id in 0..2 {
+                f.submit(client_id, round, vec![0]).unwrap();
+            }
+            let batch_id = round as usize;
+            f.manager.proposal_to_distilled(batch_id).unwrap();
+
+            let drained = f.manager.drain_distilled();
+            assert_eq!(vec![batch_id], drained.iter().map(|d| d.get_batch_id()).collect::<Vec<_>>());
+            /*only the batch in construction is left, and the ids of evicted batches are not reused */
+            assert_eq!(vec![batch_id + 1], f.manager.batches.keys().copied().collect::<Vec<_>>());
+            assert!(f.manager.get_distilled(batch_id).is_none());
+            assert_eq!(Err(BatchError::UnknownBatch(batch_id)), f.manager.proposal_to_distilled(batch_id));
+        }
+    }
+
+    #[test]
+    fn test_distilled_batches_are_handed_off_in_order() {
+        let config = BatchConfig { batch_size: 2, constructions: 2, max_proposals: 2, ..BatchConfig::default() };
+        let mut f = Fixture::new(5, config);
+        for client_id in 0..4 {
+            f.submit(client_id, 0, vec![0]).unwrap();
+        }
+        assert_eq!(2, f.manager.proposals_in_flight());
+
+        /*batch 1 waits for batch 0 */
+        f.manager.proposal_to_distilled(1).unwrap();
+        assert!(f.manager.drain_distilled().is_empty());
+        f.manager.proposal_to_distilled(0).unwrap();
+        assert_eq!(vec![0,1], f.manager.drain_distilled().iter().map(|d| d.get_batch_id()).collect::<Vec<_>>());
+
+        /*a proposal restored after a restart is newer than the empty batches in construction:
+        they are replaced once it is handed off */
+        let restored = vec![(f.addr, 4, f.submission(4, 0, vec![0]), f.keys[4].1)];
+        f.manager.restore_proposal(7, restored).unwrap();
+        f.manager.proposal_to_distilled(7).unwrap();
+        assert_eq!(vec![7], f.manager.drain_distilled().iter().map(|d| d.get_batch_id()).collect::<Vec<_>>());
+        assert_eq!(vec![8,9], f.manager.batches.keys().copied().collect::<Vec<_>>());
+        assert_eq!(8, f.submit(0, 1, vec![0]).unwrap().0);
+    }
+
+    #[test]
+    fn test_pipelined_constructions_and_proposals() {
+        let config = BatchConfig { batch_size: 2, constructions: 2, max_proposals: 1, ..BatchConfig::default() };
+        let mut f = Fixture::new(7, config);
+        assert_eq!(vec![0,1], f.manager.batches.keys().copied().collect::<Vec<_>>());
+
+        /*payloads go to the batches in construction in turn */
+        let assigned: Vec<(usize,usize,bool)> = (0..3)
+            .map(|client_id| {
+                let slot = f.manager.next_slot().unwrap();
+                let (batch_id, pos, sealed) = f.submit(client_id, 0, vec![0]).unwrap();
+                assert_eq!(slot, (batch_id, pos));
+                (batch_id, pos, sealed.is_some())
+            })
+            .collect();
+        assert_eq!(vec![(0,0,false),(1,0,false),(0,1,true)], assigned);
+
+        /*batch 0 was sealed as soon as it was full, and batch 2 opened in its place */
+        assert_eq!(1, f.manager.proposals_in_flight());
+        assert!(matches!(f.manager.batches[&2], BatchType::Construction(_)));
+
+        /*batch 1 is full too but waits, since only one proposal may collect signatures at once */
+        let (batch_id, _, sealed) = f.submit(3, 0, vec![0]).unwrap();
+        assert_eq!((1, true), (batch_id, sealed.is_none()));
+        f.submit(4, 0, vec![0]).unwrap();
+        f.submit(5, 0, vec![0]).unwrap();
+        assert_eq!(Err(BatchError::PipelineFull), f.manager.next_slot());
+        assert_eq!(Err(BatchError::PipelineFull), f.submit(6, 0, vec![0]).map(|_| ()));
+
+        /*the client is told, and keeps its payload to submit it again */
+        let (broker_sk, broker_pk) = broker_key_pair();
+        let mut pending = PendingPayloads::new(0);
+        let payload = pending.next_payload(6, vec![0]);
+        pending.submit(&payload);
+        let rejection = Rejection::from_batch_error(6, payload.seq_num, &BatchError::PipelineFull).unwrap();
+        let rejection = Rejection::from_bytes(&rejection.to_bytes(&broker_sk), &broker_pk).unwrap();
+        assert_eq!(RejectionReason::PipelineFull, rejection.reason);
+        assert_eq!(Ok(()), pending.resync(&rejection));
+        assert_eq!(Some(payload), pending.resubmission(&Resubmit { client_id: 6, seq_num: 0 }));
+        assert_eq!(None, Rejection::from_batch_error(6, 0, &BatchError::WrongState(1)));
+
+        /*once batch 0 is distilled, batch 1 goes ahead */
+        f.manager.proposal_to_distilled(0).unwrap();
+        let sealed: Vec<usize> = f.manager.poll_seal(SystemTime::now()).iter().map(|s| s.context.batch_id).collect();
+        assert_eq!(vec![1], sealed);
+    }
+
+    #[test]
+    fn test_internal_node_is_not_a_leaf() {
+        let leaves: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i;8]).collect();
+        let slices: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
+        let tree = MerkleTree::new(&slices);
+        let root = tree.get_root_hash();
+        assert_eq!(hash_leaf(&leaves[0]), tree.tree[0][0]);
+
+        /*the children of the first internal node, passed off as a 64 byte payload
+        with the part of the path of leaf 0 that is above that node */
+        let mut fake = Vec::new();
+        fake.extend_from_slice(tree.tree[0][0].as_bytes());
+        fake.extend_from_slice(tree.tree[0][1].as_bytes());
+        let mut path = tree.find_merkle_path(0).unwrap();
+        path.path.remove(0);
+        assert_eq!(Err(ProofError::WrongPosition { index: 0 }), verify_merkle_proof(&path, root, 0, &fake));
+        assert_eq!(Err(ProofError::RootMismatch), verify_merkle_proof(&MerklePath::new(path.path, 0, 2), root, 0, &fake));
+
+        /*while the real payload still verifies */
+        let path = tree.find_merkle_path(0).unwrap();
+        assert_eq!(Ok(()), verify_merkle_proof(&path, root, 0, &leaves[0]));
+    }
+
+    #[test]
+    fn test_merkle_proof_bound_to_leaf_index() {
+        let leaves: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i;8]).collect();
+        let slices: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
+        let tree = MerkleTree::new(&slices);
+        let root = tree.get_root_hash();
+
+        /*the last leaf has no sibling on the first two levels */
+        let path = tree.find_merkle_path(4).unwrap();
+        assert_eq!((4, 5, 1), (path.get_index(), path.get_leaves(), path.path.len()));
+        assert_eq!(Ok(()), path.verify(root, 4, &leaves[4]));
+
+        /*a path is only good for its own position, even for the right payload */
+        let path = tree.find_merkle_path(1).unwrap();
+        assert_eq!(Err(ProofError::WrongPosition { index: 0 }), path.verify(root, 0, &leaves[1]));
+        assert_eq!(Err(ProofError::IndexOutOfRange { index: 5, leaves: 5 }), path.verify(root, 5, &leaves[1]));
+        assert_eq!(Err(ProofError::RootMismatch), path.verify(root, 1, &leaves[2]));
+
+        /*flipping a direction makes the path claim another position */
+        let mut flipped = path.clone();
+        flipped.path[0].1 = Directions::Left;
+        assert_eq!(Err(ProofError::WrongPosition { index: 1 }), flipped.verify(root, 1, &leaves[1]));
+
+        let root_sig = RootSignature { pos: path.get_index(), signature: key_pair(0).0.sign(b"context", DST, &[]) };
+        assert_eq!(root_sig, RootSignature::from_payload(&root_sig.to_payload(3)).unwrap());
+    }
+
+    #[test]
+    fn test_merkle_multiproof() {
+        /*every set of positions of every tree of up to 9 leaves */
+        for size in 1..=9usize {
+            let leaves: Vec<Vec<u8>> = (0..size as u8).map(|i| vec![i;8]).collect();
+            let slices: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
+            let tree = MerkleTree::new(&slices);
+            let root = tree.get_root_hash();
+
+            for set in 1..(1u32 << size) {
+                let indices: Vec<usize> = (0..size).filter(|i| set & (1 << i) != 0).collect();
+                let proof = tree.find_multiproof(&indices).unwrap();
+                let proved: Vec<&[u8]> = indices.iter().map(|i| slices[*i]).collect();
+                assert_eq!(Ok(()), proof.verify(root, &proved));
+
+                let separate: usize = indices.iter().map(|i| tree.find_merkle_path(*i).unwrap().path.len()).sum();
+                assert!(proof.len() <= separate);
+                assert_eq!(proof, MerkleMultiProof::from_bytes(&proof.to_bytes()).unwrap());
+            }
+        }
+
+        let leaves: Vec<Vec<u8>> = (0..13u8).map(|i| vec![i;8]).collect();
+        let slices: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
+        let tree = MerkleTree::new(&slices);
+        let root = tree.get_root_hash();
+
+        /*positions are sorted and deduplicated, the siblings 2 and 3 share everything above them */
+        let proof = tree.find_multiproof(&[12, 3, 2, 3]).unwrap();
+        assert_eq!(&[2,3,12], proof.get_indices());
+        assert_eq!(13, proof.get_leaves());
+        assert_eq!(3, proof.len());
+        assert_eq!(Ok(()), proof.verify(root, &[slices[2], slices[3], slices[12]]));
+        assert_eq!(Err(ProofError::RootMismatch), proof.verify(root, &[slices[2], slices[4], slices[12]]));
+        assert_eq!(Err(ProofError::Malformed), proof.verify(root, &[slices[2], slices[3]]));
+
+        /*the whole tree needs no hash at all */
+        assert!(tree.find_multiproof(&(0..13).collect::<Vec<usize>>()).unwrap().is_empty());
+
+        /*a hash appended to the encoding is refused, and so is a proof padded with a hash it does not need */
+        let mut bytes = proof.to_bytes();
+        bytes.extend_from_slice(hash_leaf(b"extra").as_bytes());
+        assert!(MerkleMultiProof::from_bytes(&bytes).is_err());
+        let count = bytes.len() - 32 - 8 - 3 * 32;
+        bytes[count + 7] += 1;
+        let padded = MerkleMultiProof::from_bytes(&bytes).unwrap();
+        assert_eq!(Err(ProofError::Malformed), padded.verify(root, &[slices[2], slices[3], slices[12]]));
+        assert!(MerkleMultiProof::from_bytes(&bytes[..20]).is_err());
+    }
+
+    #[test]
+    fn test_merkle_builder_matches_tree() {
+        for size in [0usize, 1, 2, 3, 4, 5, 7, 8, 9, 31, 100, 1025] {
+            let leaves: Vec<Vec<u8>> = (0..size as u64).map(|x| x.to_be_bytes().to_vec()).collect();
+            let slices: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
+
+            let mut builder = MerkleBuilder::new();
+            for leaf in &slices {
+                builder.push(leaf);
+            }
+            assert_eq!(size, builder.len());
+
+            /*the batch tree used to be built level by level at seal time */
+            let mut expected: Vec<Vec<Hash>> = vec![slices.iter().map(|x| hash_leaf(x)).collect()];
+            while expected.last().unwrap().len() > 1 {
+                let next = expected.last().unwrap().chunks(2).map(|pair| match pair {
+                    [left, right] => blake3::Hasher::new().update(&[1]).update(left.as_bytes()).update(right.as_bytes()).finalize(),
+                    [single] => blake3::Hasher::new().update(&[2]).update(single.as_bytes()).finalize(),
+                    _ => unreachable!(),
+                }).collect();
+                expected.push(next);
+            }
+            assert_eq!(expected, builder.finish().tree);
+        }
+
+        /*the proposal gets the tree built while its batch was in construction */
+        let mut f = Fixture::new(3, BatchConfig { batch_size: 3, ..BatchConfig::default() });
+        let mut sealed = None;
+        let mut payloads = Vec::new();
+        for client_id in 0..3u64 {
+            payloads.push(Payload::new(client_id, 0, vec![client_id as u8;4]).to_bytes());
+            sealed = f.submit(client_id, 0, vec![client_id as u8;4]).unwrap().2;
+        }
+        let leaves: Vec<&[u8]> = payloads.iter().map(|x| &x[..]).collect();
+        assert_eq!(MerkleTree::new(&leaves).get_root_hash(), sealed.unwrap().context.root);
+    }
+
+    #[test]
+    fn test_parallel_merkle_matches_tree() {
+        for size in [0usize, 1, 2, 3, 5, 7, 8, 17, 63, 1000, 4097] {
+            let leaves: Vec<Vec<u8>> = (0..size as u64).map(|x| x.to_be_bytes().repeat(8)).collect();
+            let slices: Vec<&[u8]> = leaves.iter().map(|x| &x[..]).collect();
+            let tree = MerkleTree::new(&slices);
+
+            for threads in [0, 1, 2, 3, 4, 5, 8, usize::MAX] {
+                let parallel = MerkleTree::new_parallel(&slices, threads);
+                assert_eq!(tree.tree, parallel.tree, "{} leaves on {} threads", size, threads);
+                if size > 0 {
+                    assert_eq!(tree.get_root_hash(), parallel.get_root_hash(), "{} leaves on {} threads", size, threads);
+                }
+            }
+            /*whatever the number of cores of the machine running the test */
+            for chunk_len in [1, 2, 3, 4, 8, 64] {
+                assert_eq!(tree.tree, MerkleTree::from_chunks(&slices, chunk_len).tree, "{} leaves in chunks of {}", size, chunk_len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_client_goes_through_every_state() {
+        let mut f = Fixture::new(3, BatchConfig { batch_size: 2, ..BatchConfig::default() });
+        let mut states = ClientStates::new();
+        for client_id in 0..3 {
+            states.add(client_id);
+        }
+
+        let mut sealed = None;
+        for client_id in [2u64, 0] {
+            let (batch_id, pos, s) = f.submit(client_id, 0, vec![0]).unwrap();
+            states.assign(client_id, batch_id, pos).unwrap();
+            sealed = s;
+        }
+        assert_eq!(Some(ClientState::AssignedToBatch(0,1)), states.get(0));
+        assert_eq!(Err(InvalidTransition), states.assign(0, 1, 0));
+
+        let sealed = sealed.expect("batch should be sealed");
+        states.wait_for_signature(&sealed);
+        assert_eq!(Some(ClientState::WaitingForSignature(0,0)), states.get(2));
+        assert_eq!(Some(ClientState::WaitingForSignature(0,1)), states.get(0));
+        assert_eq!(Some(ClientState::NotAssignedToBatch), states.get(1));
+        assert_eq!(None, states.get(3));
+
+        f.manager.proposal_to_distilled(0).unwrap();
+        assert_eq!(2, states.release(0));
+        assert_eq!(0, states.release(0));
+        assert_eq!(Some(ClientState::NotAssignedToBatch), states.get(2));
+
+        /*a released client can take part in the next batch */
+        let (batch_id, pos, _) = f.submit(2, 1, vec![0]).unwrap();
+        states.assign(2, batch_id, pos).unwrap();
+        assert_eq!(Some(ClientState::AssignedToBatch(1,0)), states.get(2));
+    }
+
+    #[test]
+    fn test_registry_grows_and_round_trips() {
+        let keys: Vec<PublicKey> = (0..3).map(|i| key_pair(i).1).collect();
+        let mut registry = ClientRegistry::from_pks(keys[..2].to_vec());
+        assert!(matches!(registry.get_pk(1_000_000), Err(RegistryError::UnknownClient(1_000_000))));
+
+        /*ids do not have to be contiguous */
+        registry.register(1_000_000, keys[2]).unwrap();
+        registry.register(1_000_000, keys[2]).unwrap();
+        assert!(matches!(registry.register(1, keys[2]), Err(RegistryError::AlreadyRegistered(1))));
+        /*a key can't be registered under a second id */
+        assert!(matches!(registry.register(5, keys[2]), Err(RegistryError::KeyAlreadyRegistered(1_000_000))));
+        assert!(matches!(registry.get_pk(5), Err(RegistryError::UnknownClient(5))));
+        assert_eq!(keys[2], registry.get_pk(1_000_000).unwrap());
+        assert_eq!(ClientState::NotAssignedToBatch, registry.get_state(1_000_000).unwrap());
+        assert!(matches!(registry.assign(7, 0, 0), Err(RegistryError::UnknownClient(7))));
+
+        let path = TempPath::new("registry");
+        registry.save(&path).unwrap();
+        let loaded = ClientRegistry::load(&path).unwrap();
+        assert_eq!(3, loaded.len());
+        for client_id in [0, 1, 1_000_000] {
+            assert_eq!(registry.get_pk(client_id).unwrap(), loaded.get_pk(client_id).unwrap());
+        }
+
+        let bytes = registry.to_bytes();
+        assert!(matches!(ClientRegistry::from_bytes(&bytes[..bytes.len()-1]), Err(RegistryError::NotARegistry)));
+        assert!(matches!(ClientRegistry::from_bytes(&bytes[1..]), Err(RegistryError::NotARegistry)));
+    }
+
+    #[test]
+    fn test_sign_ups_are_appended_to_the_file() {
+        let keys: Vec<PublicKey> = (0..4).map(|i| key_pair(i).1).collect();
+        let mut registry = ClientRegistry::from_pks(keys[..2].to_vec());
+        let path = TempPath::new("appended_registry");
+        registry.save(&path).unwrap();
+
+        for pk in &keys[2..] {
+            let client_id = registry.len() as u64;
+            registry.register(client_id, *pk).unwrap();
+            registry.append_client(&path, client_id).unwrap();
+            assert_eq!(registry.to_bytes(), std::fs::read(&path).unwrap());
+        }
+        assert!(matches!(registry.append_client(&path, 7), Err(RegistryError::UnknownClient(7))));
+
+        /*an entry whose count was not updated, e.g. after a crash, is not loaded and is
+        overwritten by the next one */
+        let bytes = std::fs::read(&path).unwrap();
+        let mut torn = bytes.clone();
+        torn[24] -= 1;
+        std::fs::write(&path, &torn).unwrap();
+        assert_eq!(3, ClientRegistry::load(&path).unwrap().len());
+        registry.append_client(&path, 3).unwrap();
+        assert_eq!(bytes, std::fs::read(&path).unwrap());
+
+        std::fs::write(&path, b"not a registry").unwrap();
+        assert!(matches!(registry.append_client(&path, 3), Err(RegistryError::NotARegistry)));
+    }
+
+    #[test]
+    fn test_forged_submissions_are_not_authenticated() {
+        let keys = [key_pair(0), key_pair(1)];
+        let registry = ClientRegistry::from_pks(keys.iter().map(|(_,pk)| *pk).collect());
+
+        let genuine = Submission::sign(Payload::new(0, 0, vec![1,2,3]), &keys[0].0, 0);
+        assert_eq!(keys[0].1, registry.authenticate(0, &genuine).unwrap());
+
+        /*client 1 claims the id of client 0 */
+        let forged = Submission::sign(Payload::new(0, 0, vec![1,2,3]), &keys[1].0, 0);
+        assert!(matches!(registry.authenticate(0, &forged), Err(RegistryError::UnauthenticatedSubmission(0))));
+
+        /*a submission meant for another broker is not valid here */
+        let replayed = Submission::sign(Payload::new(0, 0, vec![1,2,3]), &keys[0].0, 1);
+        assert!(matches!(registry.authenticate(0, &replayed), Err(RegistryError::UnauthenticatedSubmission(0))));
+
+        let unknown = Submission::sign(Payload::new(5, 0, vec![1,2,3]), &keys[0].0, 0);
+        assert!(matches!(registry.authenticate(0, &unknown), Err(RegistryError::UnknownClient(5))));
+    }
+
+    #[test]
+    fn test_delivered_sequence_numbers_cannot_be_replayed() {
+        let mut registry = ClientRegistry::from_pks(vec![key_pair(0).1]);
+        let mut pending = PendingPayloads::new(0);
+
+        let first = pending.next_payload(0, vec![1]);
+        let second = pending.next_payload(0, vec![2]);
+        assert_eq!((0,1), (first.seq_num, second.seq_num));
+
+        assert!(registry.check_fresh(&first).is_ok());
+        registry.record_delivered(&[&first]);
+        let res = registry.check_fresh(&first);
+        assert!(matches!(res, Err(RegistryError::StaleSequenceNumber { client_id: 0, seq_num: 0, last_delivered: 0 })));
+        assert!(registry.check_fresh(&second).is_ok());
+
+        /*a client that submits its payload again is told it was already delivered */
+        let (broker_sk, broker_pk) = broker_key_pair();
+        let mut restarted = PendingPayloads::new(0);
+        let replayed = restarted.next_payload(0, vec![1]);
+        restarted.submit(&replayed);
+        let rejection = match registry.check_fresh(&replayed) {
+            Err(e @ RegistryError::StaleSequenceNumber { .. }) => Rejection::from_error(&e).unwrap(),
+            res => panic!("expected a stale sequence number, got {:?}", res),
+        };
+        let rejection = Rejection::from_bytes(&rejection.to_bytes(&broker_sk), &broker_pk).unwrap();
+        assert_eq!(Ok(()), restarted.resync(&rejection));
+        assert!(restarted.is_empty());
+        /*the same rejection sent again has nothing left to drop */
+        assert!(restarted.resync(&rejection).is_err());
+        assert_eq!(1, restarted.next_payload(0, vec![2]).seq_num);
+    }
+
+    #[test]
+    fn test_second_payload_in_flight_is_rejected() {
+        let (broker_sk, broker_pk) = broker_key_pair();
+        let mut registry = ClientRegistry::from_pks(vec![key_pair(0).1]);
+        let mut pending = PendingPayloads::new(0);
+        let first = pending.next_payload(0, vec![1]);
+        let second = pending.next_payload(0, vec![2]);
+        pending.submit(&first);
+        pending.submit(&second);
+
+        /*the first payload is in batch 4, the same payload or the next one can't be submitted
+        until it is delivered */
+        registry.assign(0, 4, 0).unwrap();
+        for payload in [&first, &second] {
+            let res = registry.check_fresh(payload);
+            assert!(matches!(res, Err(RegistryError::PayloadInFlight { client_id: 0, batch_id: 4, .. })));
+            let rejection = Rejection::from_bytes(&Rejection::from_error(&res.unwrap_err()).unwrap().to_bytes(&broker_sk), &broker_pk).unwrap();
+            assert_eq!(RejectionReason::InFlight { batch_id: 4 }, rejection.reason);
+            assert_eq!(payload.seq_num, rejection.seq_num);
+
+            /*the client keeps both payloads pending and its sequence numbers as they are */
+            assert_eq!(Ok(()), pending.resync(&rejection));
+            assert_eq!(2, pending.len());
+        }
+
+        /*a replay is reported as such even while another payload is in flight */
+        registry.record_delivered(&[&first]);
+        assert!(matches!(registry.check_fresh(&first), Err(RegistryError::StaleSequenceNumber { .. })));
+        assert!(matches!(registry.check_fresh(&second), Err(RegistryError::PayloadInFlight { .. })));
+        assert_eq!(2, pending.next_payload(0, vec![3]).seq_num);
+    }
+
+    #[test]
+    fn test_sign_up_requires_proof_of_possession() {
+        let keys: Vec<(SecretKey,PublicKey)> = (0..3).map(key_pair).collect();
+        let mut registry = ClientRegistry::from_pks(vec![keys[0].1]);
+
+        let signup = SignUp::from_bytes(&SignUp::new(&keys[1].0).to_bytes()).unwrap();
+        assert_eq!(1, registry.sign_up(&signup).unwrap());
+        /*signing up twice gives back the same id */
+        assert_eq!(1, registry.sign_up(&signup).unwrap());
+        assert_eq!(keys[1].1, registry.get_pk(1).unwrap());
+
+        /*a key whose secret key is not known to the client (a rogue key) cannot be registered */
+        let rogue = SignUp { pk: keys[2].1, pop: SignUp::new(&keys[1].0).pop };
+        assert!(matches!(registry.sign_up(&rogue), Err(RegistryError::InvalidProofOfPossession)));
+        /*neither can a signature over the key made for another purpose */
+        let wrong_dst = SignUp { pk: keys[2].1, pop: keys[2].0.sign(&keys[2].1.compress(), DST, &[]) };
+        assert!(matches!(registry.sign_up(&wrong_dst), Err(RegistryError::InvalidProofOfPossession)));
+        assert_eq!(2, registry.len());
+
+        let reply = SignUpReply { client_id: 1, pk: keys[1].1 };
+        assert_eq!(reply, SignUpReply::from_bytes(&reply.to_bytes()).unwrap());
+        assert!(SignUp::from_bytes(&reply.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_rejection_round_trip() {
+        let (broker_sk, broker_pk) = broker_key_pair();
+        let in_flight = Rejection { client_id: 3, seq_num: 6, reason: RejectionReason::InFlight { batch_id: 12 } };
+        assert_eq!(in_flight, Rejection::from_bytes(&in_flight.to_bytes(&broker_sk), &broker_pk).unwrap());
+        let mut unknown_reason = in_flight.to_bytes(&broker_sk);
+        unknown_reason[32] = 3;
+        assert_eq!(Err(NoticeError::NotANotice), Rejection::from_bytes(&unknown_reason, &broker_pk));
+
+        let rejection = Rejection { client_id: 3, seq_num: 5, reason: RejectionReason::Stale { last_delivered: 9 } };
+        let bytes = rejection.to_bytes(&broker_sk);
+        assert_eq!(Rejection::SIZE, bytes.len());
+        assert_eq!(rejection, Rejection::from_bytes(&bytes, &broker_pk).unwrap());
+
+        assert!(Rejection::from_bytes(&bytes[..Rejection::SIZE-1], &broker_pk).is_err());
+        assert!(Rejection::from_bytes(&bytes[1..], &broker_pk).is_err());
+        assert!(Rejection::from_bytes(b"RAINFALL_SIGNUP1 and then forty bytes or so", &broker_pk).is_err());
+
+        /*a rejection signed by someone else, or changed after it was signed, is refused */
+        assert_eq!(Err(NoticeError::InvalidSignature), Rejection::from_bytes(&rejection.to_bytes(&key_pair(0).0), &broker_pk));
+        let mut tampered = bytes.clone();
+        tampered[40] ^= 1;
+        assert_eq!(Err(NoticeError::InvalidSignature), Rejection::from_bytes(&tampered, &broker_pk));
+    }
+
+    #[test]
+    fn test_forged_rejections_drop_nothing() {
+        let (broker_sk, broker_pk) = broker_key_pair();
+        let mut pending = PendingPayloads::new(0);
+        for message in 0..3u8 {
+            let payload = pending.next_payload(2, vec![message]);
+            pending.submit(&payload);
+        }
+
+        /*anyone can send the client a rejection claiming every sequence number was delivered */
+        let spoofed = Rejection { client_id: 2, seq_num: 0, reason: RejectionReason::Stale { last_delivered: u64::MAX } };
+        assert_eq!(Err(NoticeError::InvalidSignature), Rejection::from_bytes(&spoofed.to_bytes(&key_pair(2).0), &broker_pk));
+
+        /*even the broker can't claim it delivered a sequence number the client never gave out,
+        or reject a payload the client is not waiting on */
+        let spoofed = Rejection::from_bytes(&spoofed.to_bytes(&broker_sk), &broker_pk).unwrap();
+        assert_eq!(Err(BrokerMisbehaviour::InvalidRejection { client_id: 2, seq_num: 0 }), pending.resync(&spoofed));
+        let beyond = Rejection { client_id: 2, seq_num: 1, reason: RejectionReason::Stale { last_delivered: 3 } };
+        assert!(pending.resync(&beyond).is_err());
+        let unknown = Rejection { client_id: 2, seq_num: 7, reason: RejectionReason::Stale { last_delivered: 0 } };
+        assert!(pending.resync(&unknown).is_err());
+        assert_eq!(3, pending.len());
+        assert_eq!(3, pending.next_payload(2, vec![3]).seq_num);
+
+        let genuine = Rejection { client_id: 2, seq_num: 0, reason: RejectionReason::Stale { last_delivered: 1 } };
+        assert_eq!(Ok(()), pending.resync(&genuine));
+        assert_eq!(1, pending.len());
+    }
+
+    #[test]
+    fn test_resubmit_round_trip() {
+        let (broker_sk, broker_pk) = broker_key_pair();
+        let resubmit = Resubmit { client_id: 4, seq_num: 0 };
+        let bytes = resubmit.to_bytes(&broker_sk);
+        assert_eq!(Resubmit::SIZE, bytes.len());
+        assert_eq!(resubmit, Resubmit::from_bytes(&bytes, &broker_pk).unwrap());
+        assert_eq!(Err(NoticeError::NotANotice), Resubmit::from_bytes(&bytes[..Resubmit::SIZE-1], &broker_pk));
+        assert_eq!(Err(NoticeError::NotANotice), Rejection::from_bytes(&bytes, &broker_pk));
+
+        /*a request to resubmit that the broker did not sign is refused */
+        assert_eq!(Err(NoticeError::InvalidSignature), Resubmit::from_bytes(&resubmit.to_bytes(&key_pair(4).0), &broker_pk));
+        let mut tampered = bytes.clone();
+        tampered[31] ^= 1;
+        assert_eq!(Err(NoticeError::InvalidSignature), Resubmit::from_bytes(&tampered, &broker_pk));
+
+        /*the client sends the lost payload again, as long as it is still pending */
+        let mut pending = PendingPayloads::new(0);
+        let payload = pending.next_payload(4, vec![4;4]);
+        pending.submit(&payload);
+        assert_eq!(Some(payload), pending.resubmission(&resubmit));
+        assert_eq!(None, pending.resubmission(&Resubmit { client_id: 4, seq_num: 1 }));
+    }
+
+    #[test]
+    fn test_batch_log_round_trip_and_corruption() {
+        let mut f = Fixture::new(3, BatchConfig { batch_size: 3, ..BatchConfig::default() });
+        let registry = f.registry();
+
+        /*in batch 0 clients 0 and 1 sign and client 2 is an exception, in batch 1 nobody signs */
+        for batch_id in 0..2 {
+            let mut sealed = None;
+            for client_id in 0..3 {
+                sealed = f.submit(client_id, batch_id as u64, vec![batch_id as u8;4]).unwrap().2;
+            }
+            let sealed = sealed.expect("batch should be sealed");
+            for pos in 0..(if batch_id == 0 { 2 } else { 0 }) {
+                f.sign(&sealed, pos).unwrap();
+            }
+            f.manager.proposal_to_distilled(batch_id).unwrap();
+        }
+
+        let path = TempPath::new("batch_log");
+        let drained = f.manager.drain_distilled();
+        let mut log = BatchLog::open(&path).unwrap();
+        log.append(&drained[0]).unwrap();
+        drop(log);
+        /*reopening appends after the existing records */
+        BatchLog::open(&path).unwrap().append(&drained[1]).unwrap();
+
+        let logged: Vec<LoggedBatch> = BatchLogReader::open(&path).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(vec![LoggedBatch::from_distilled(&drained[0]), LoggedBatch::from_distilled(&drained[1])], logged);
+        assert_eq!(vec![true,true,false], logged[0].bitmap);
+        assert_eq!(3, logged[0].delivered_payloads().len());
+        assert!(logged[1].aggregate.is_none());
+        assert!(logged.iter().all(|batch| batch.verify(&registry)));
+
+        /*a record whose aggregate does not match its bitmap does not verify */
+        let mut forged = logged[0].clone();
+        forged.bitmap[2] = true;
+        assert!(!forged.verify(&registry));
+
+        let bytes = std::fs::read(&path).unwrap();
+        let mut corrupted = bytes.clone();
+        corrupted[17 + 8 + 70] ^= 1;
+        std::fs::write(&path, &corrupted).unwrap();
+        let records: Vec<Result<LoggedBatch,LogError>> = BatchLogReader::open(&path).unwrap().collect();
+        assert!(matches!(records[..], [Err(LogError::Corrupted { record: 0 })]));
+
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let records: Vec<Result<LoggedBatch,LogError>> = BatchLogReader::open(&path).unwrap().collect();
+        assert!(matches!(records[..], [Ok(_), Err(LogError::Truncated { record: 1 })]));
+
+        std::fs::write(&path, b"not a batch log").unwrap();
+        assert!(matches!(BatchLogReader::open(&path), Err(LogError::UnexpectedHeader)));
+    }
+
+    #[test]
+    fn test_batch_log_refuses_inconsistent_records() {
+        let mut f = Fixture::new(3, BatchConfig { batch_size: 3, ..BatchConfig::default() });
+        let mut sealed = None;
+        for client_id in 0..3 {
+            sealed = f.submit(client_id, 0, vec![client_id as u8;8]).unwrap().2;
+        }
+        let sealed = sealed.expect("batch should be sealed");
+        f.sign(&sealed, 0).unwrap();
+        f.manager.proposal_to_distilled(0).unwrap();
+        let logged = LoggedBatch::from_distilled(&f.manager.drain_distilled()[0]);
+
+        /*every record below has a valid checksum, only its content is inconsistent */
+        let read_back = |batch: &LoggedBatch| {
+            let mut bytes = Vec::new();
+            write_header(&mut bytes, LOG_TAG, LOG_VERSION).unwrap();
+            bytes.extend_from_slice(&batch.to_record());
+            BatchLogReader::from_reader(&bytes[..]).unwrap().collect::<Vec<_>>()
+        };
+        assert!(matches!(read_back(&logged)[..], [Ok(_)]));
+
+        let mut out_of_range = logged.clone();
+        out_of_range.exceptions[0].position = 3;
+        assert!(matches!(read_back(&out_of_range)[..], [Err(LogError::Corrupted { record: 0 })]));
+
+        let mut duplicated = logged.clone();
+        duplicated.exceptions[1].position = duplicated.exceptions[0].position;
+        assert!(matches!(read_back(&duplicated)[..], [Err(LogError::Corrupted { record: 0 })]));
+
+        let mut signed_twice = logged.clone();
+        signed_twice.exceptions[0].position = 0;
+        assert!(matches!(read_back(&signed_twice)[..], [Err(LogError::Corrupted { record: 0 })]));
+
+        /*a bitmap longer than the batch sets a padding bit */
+        let mut long_bitmap = logged.clone();
+        long_bitmap.bitmap.push(true);
+        assert!(matches!(read_back(&long_bitmap)[..], [Err(LogError::Corrupted { record: 0 })]));
+        assert!(!long_bitmap.verify(&f.registry()));
+        assert_eq!(3, long_bitmap.delivered_payloads().len());
+    }
+
+    #[test]
+    fn test_recover_after_crash() {
+        let config = BatchConfig { batch_size: 2, constructions: 2, ..BatchConfig::default() };
+        let mut f = Fixture::new(5, config);
+        let wal_path = TempPath::new("recovery_wal");
+        let log_path = TempPath::new("recovery_log");
+
+        /*as the server does: every assignment and every seal is written ahead */
+        let mut wal = BatchWal::open(&wal_path).unwrap();
+        let mut sealed = Vec::new();
+        let mut batch_of = Vec::new();
+        for client_id in 0..5 {
+            let submission = f.submission(client_id, 7, vec![client_id as u8;4]);
+            let (batch_id,pos,new_seal) = f.submit(client_id, 7, vec![client_id as u8;4]).unwrap();
+            wal.append(&WalRecord::Assigned { batch_id, pos, client_id, addr: f.addr, submission: Box::new(submission) }).unwrap();
+            if let Some(new_seal) = new_seal {
+                wal.append(&WalRecord::Sealed { batch_id }).unwrap();
+                sealed.push(new_seal);
+            }
+            batch_of.push(batch_id);
+        }
+        wal.flush().unwrap();
+        assert_eq!(2, sealed.len());
+
+        /*the first proposal is distilled and logged, the second one is in flight and the
+        last client is in a batch in construction when the broker crashes mid-write */
+        let (delivered, in_flight) = (sealed[0].context.batch_id, &sealed[1]);
+        f.manager.proposal_to_distilled(delivered).unwrap();
+        BatchLog::open(&log_path).unwrap().append(&f.manager.drain_distilled()[0]).unwrap();
+        drop(wal);
+        let mut bytes = std::fs::read(&log_path).unwrap();
+        bytes.extend_from_slice(&[0u8;12]);
+        std::fs::write(&log_path, &bytes).unwrap();
+        let mut bytes = std::fs::read(&wal_path).unwrap();
+        bytes.extend_from_slice(&[1u8;5]);
+        std::fs::write(&wal_path, &bytes).unwrap();
+
+        let mut registry = f.registry();
+        let mut recovery = recover(0, config, &mut registry, &wal_path, &log_path).unwrap();
+        let in_flight_id = in_flight.context.batch_id;
+        assert_eq!(vec![in_flight_id], recovery.proposals.iter().map(|sealed| sealed.context.batch_id).collect::<Vec<_>>());
+        /*the proofs sent again are the ones the clients got before the crash */
+        let restored = &recovery.proposals[0];
+        assert_eq!(in_flight.client_ids, restored.client_ids);
+        assert_eq!(in_flight.merkle.get_root_hash(), restored.merkle.get_root_hash());
+        for (pos,client_id) in in_flight.client_ids.iter().enumerate() {
+            assert_eq!(ClientState::WaitingForSignature(in_flight_id, pos), registry.get_state(*client_id).unwrap());
+        }
+        assert_eq!(vec![Resubmit { client_id: 4, seq_num: 7 }], recovery.resubmits.iter().map(|(_,r)| *r).collect::<Vec<_>>());
+        assert_eq!(ClientState::NotAssignedToBatch, registry.get_state(4).unwrap());
+        assert!(matches!(registry.check_fresh(&Payload::new(sealed[0].client_ids[0], 7, vec![])), Err(RegistryError::StaleSequenceNumber { .. })));
+
+        /*the restored proposal has the root the clients signed before the crash */
+        for (pos,client_id) in in_flight.client_ids.iter().enumerate() {
+            let (sk,pk) = &f.keys[*client_id as usize];
+            let sig = sk.sign(&in_flight.context.to_bytes(), DST, &[]);
+            recovery.manager.add_to_proposal(in_flight_id, pos, sig, *pk).unwrap();
+        }
+        assert_eq!(vec![true,true], recovery.manager.drain_distilled()[0].bitmap);
+
+        /*ids of batches from before the crash are not given again */
+        let submission = f.submission(4, 7, vec![4;4]);
+        let (batch_id,_,_) = recovery.manager.add_to_construction(f.addr, 4, submission, f.keys[4].1).unwrap();
+        assert!(batch_id > *batch_of.iter().max().unwrap());
+
+        /*only the records of the restored proposal are kept, and the batch log is whole again */
+        let records: Vec<WalRecord> = WalReader::open(&wal_path).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(3, records.len());
+        assert!(records.iter().all(|r| r.get_batch_id() == in_flight_id));
+        assert!(BatchLogReader::open(&log_path).unwrap().all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn test_recover_refuses_corrupted_logs() {
+        let config = BatchConfig { batch_size: 2, ..BatchConfig::default() };
+        let mut f = Fixture::new(4, config);
+        let wal_path = TempPath::new("corrupted_wal");
+        let log_path = TempPath::new("corrupted_log");
+
+        let mut wal = BatchWal::open(&wal_path).unwrap();
+        let mut log = BatchLog::open(&log_path).unwrap();
+        for client_id in 0..4 {
+            let submission = f.submission(client_id, 0, vec![client_id as u8;4]);
+            let (batch_id,pos,sealed) = f.submit(client_id, 0, vec![client_id as u8;4]).unwrap();
+            wal.append(&WalRecord::Assigned { batch_id, pos, client_id, addr: f.addr, submission: Box::new(submission) }).unwrap();
+            if sealed.is_some() {
+                wal.append(&WalRecord::Sealed { batch_id }).unwrap();
+                f.manager.proposal_to_distilled(batch_id).unwrap();
+                log.append(&f.manager.drain_distilled()[0]).unwrap();
+            }
+        }
+        drop(wal);
+        drop(log);
+
+        /*a byte flipped in the first record of either log, with whole records after it */
+        for (path, other) in [(&log_path, &wal_path), (&wal_path, &log_path)] {
+            let bytes = std::fs::read(path).unwrap();
+            let other_bytes = std::fs::read(other).unwrap();
+            let mut corrupted = bytes.clone();
+            corrupted[17 + 8 + 1] ^= 1;
+            std::fs::write(path, &corrupted).unwrap();
+
+            let result = recover(0, config, &mut f.registry(), &wal_path, &log_path);
+            assert!(matches!(result, Err(LogError::Corrupted { record: 0 })));
+            /*neither log was cut or rewritten */
+            assert_eq!(corrupted, std::fs::read(path).unwrap());
+            assert_eq!(other_bytes, std::fs::read(other).unwrap());
+
+            std::fs::write(path, &bytes).unwrap();
+        }
+        assert!(recover(0, config, &mut f.registry(), &wal_path, &log_path).is_ok());
+    }
+
+    #[test]
+    fn test_recover_refuses_a_corrupted_record_length() {
+        let config = BatchConfig { batch_size: 2, ..BatchConfig::default() };
+        let mut f = Fixture::new(6, config);
+        let wal_path = TempPath::new("corrupted_len_wal");
+        let log_path = TempPath::new("corrupted_len_log");
+
+        let mut log = BatchLog::open(&log_path).unwrap();
+        for client_id in 0..6 {
+            if let (batch_id,_,Some(_)) = f.submit(client_id, 0, vec![client_id as u8;4]).unwrap() {
+                f.manager.proposal_to_distilled(batch_id).unwrap();
+                log.append(&f.manager.drain_distilled()[0]).unwrap();
+            }
+        }
+        drop(log);
+        let bytes = std::fs::read(&log_path).unwrap();
+        let first_len = u64::from_be_bytes(bytes[17..25].try_into().unwrap()) as usize;
+        let middle = 17 + 8 + first_len + 32;
+
+        /*the length of the middle record claims more than is left in the log, so the records
+        after it look like the tail of a record cut short */
+        for len in [u64::MAX, 1 << 40, (bytes.len() as u64) << 32] {
+            let mut corrupted = bytes.clone();
+            corrupted[middle..middle + 8].copy_from_slice(&len.to_be_bytes());
+            std::fs::write(&log_path, &corrupted).unwrap();
+
+            let result = recover(0, config, &mut f.registry(), &wal_path, &log_path);
+            assert!(matches!(result, Err(LogError::Corrupted { record: 1 })));
+            assert_eq!(corrupted, std::fs::read(&log_path).unwrap());
+        }
+
+        /*while a record that really is the last one and cut short is still dropped */
+        std::fs::write(&log_path, &bytes[..bytes.len() - 10]).unwrap();
+        assert!(recover(0, config, &mut f.registry(), &wal_path, &log_path).is_ok());
+        let records: Vec<Result<_,LogError>> = BatchLogReader::open(&log_path).unwrap().collect();
+        assert!(records.len() == 2 && records.iter().all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn test_wal_round_trip() {
+        let f = Fixture::new(1, BatchConfig::default());
+        let path = TempPath::new("wal");
+
+        let mut addr = f.addr;
+        addr.sin_port = 4242u16.to_be();
+        addr.sin_addr.s_addr = u32::from_be_bytes([10,0,0,1]).to_be();
+        let assigned = WalRecord::Assigned { batch_id: 3, pos: 1, client_id: 0, addr, submission: Box::new(f.submission(0, 9, vec![1,2,3])) };
+        let mut wal = BatchWal::open(&path).unwrap();
+        wal.append(&assigned).unwrap();
+        wal.append(&WalRecord::Sealed { batch_id: 3 }).unwrap();
+        drop(wal);
+
+        let records: Vec<WalRecord> = WalReader::open(&path).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(2, records.len());
+        match &records[0] {
+            WalRecord::Assigned { batch_id, pos, client_id, addr: read_addr, submission } => {
+                assert_eq!((3, 1, 0), (*batch_id, *pos, *client_id));
+                assert_eq!((addr.sin_port, addr.sin_addr.s_addr), (read_addr.sin_port, read_addr.sin_addr.s_addr));
+                assert_eq!(f.submission(0, 9, vec![1,2,3]).to_bytes(), submission.to_bytes());
+            },
+            record => panic!("expected an assignment, got {:?}", record),
+        }
+        assert!(matches!(records[1], WalRecord::Sealed { batch_id: 3 }));
+
+        /*a record cut short ends the log */
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+        let records: Vec<Result<WalRecord,LogError>> = WalReader::open(&path).unwrap().collect();
+        assert!(matches!(records[..], [Ok(WalRecord::Assigned { .. }), Err(LogError::Truncated { record: 1 })]));
+
+        /*rewriting keeps only the records given */
+        BatchWal::rewrite(&path, &[WalRecord::Sealed { batch_id: 4 }]).unwrap();
+        let records: Vec<WalRecord> = WalReader::open(&path).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(vec![4], records.iter().map(|r| r.get_batch_id()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_wal_is_compacted_once_batches_are_delivered() {
+        let f = Fixture::new(2, BatchConfig::default());
+        let path = TempPath::new("compacted_wal");
+        let assigned = |batch_id: BatchId, client_id: u64| WalRecord::Assigned { batch_id, pos: client_id as usize, client_id, addr: f.addr, submission: Box::new(f.submission(client_id, batch_id as u64, vec![0;8])) };
+
+        /*batch 0 stays in flight while the batches after it come and go */
+        let mut wal = BatchWal::open(&path).unwrap();
+        wal.append(&assigned(0, 0)).unwrap();
+        let mut longest = 0;
+        for batch_id in 1..200 {
+            for client_id in 0..2 {
+                wal.append(&assigned(batch_id, client_id)).unwrap();
+            }
+            wal.append(&WalRecord::Sealed { batch_id }).unwrap();
+            wal.delivered(batch_id).unwrap();
+            longest = longest.max(wal.len());
+        }
+        assert!(longest <= COMPACT_AFTER + 4);
+        wal.append(&WalRecord::Sealed { batch_id: 0 }).unwrap();
+
+        /*what is left on disk is enough to recover from, and is read back by `open` */
+        wal.compact().unwrap();
+        drop(wal);
+        let records: Vec<WalRecord> = WalReader::open(&path).unwrap().map(|r| r.unwrap()).collect();
+        assert!(matches!(records[..], [WalRecord::Assigned { batch_id: 0, .. }, WalRecord::Sealed { batch_id: 0 }]));
+        let mut wal = BatchWal::open(&path).unwrap();
+        assert_eq!(2, wal.len());
+        wal.delivered(0).unwrap();
+        wal.compact().unwrap();
+        assert!(wal.is_empty());
+        assert_eq!(0, WalReader::open(&path).unwrap().count());
+    }
+
+    /*Distills a batch of 3 payloads in which the clients at `signers` signed the root,
+    the other clients being exceptions */
+    fn distill(f: &mut Fixture, seq_num: u64, signers: &[usize]) -> LoggedBatch {
+        let mut sealed = None;
+        for client_id in 0..3 {
+            sealed = f.submit(client_id, seq_num, vec![client_id as u8;4]).unwrap().2;
+        }
+        let sealed = sealed.expect("batch should be sealed");
+        for pos in signers {
+            f.sign(&sealed, *pos).unwrap();
+        }
+        f.manager.proposal_to_distilled(sealed.context.batch_id).unwrap();
+        LoggedBatch::from_distilled(&f.manager.drain_distilled()[0])
+    }
+
+    #[test]
+    fn test_downstream_checks_and_delivers_batches() {
+        let mut f = Fixture::new(3, BatchConfig { batch_size: 3, ..BatchConfig::default() });
+
+        /*clients 0 and 2 sign, client 1 is an exception */
+        let batch = distill(&mut f, 0, &[0,2]);
+
+        /*the batch goes through the stream the broker sends to the server */
+        let mut stream = Vec::new();
+        BatchSender::new(&mut stream).unwrap().send(&batch).unwrap();
+        let received: Vec<LoggedBatch> = BatchLogReader::from_reader(&stream[..]).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(vec![batch.clone()], received);
+
+        let mut delivered = Vec::new();
+        let mut downstream = Downstream::new(0, ClientRegistry::from_pks(vec![f.keys[0].1]), |batch_id, payload: &Payload| delivered.push((batch_id, payload.num_id)));
+        assert_eq!(Err(DeliveryError::UnknownClient { batch_id: 0, client_id: 1 }), downstream.receive(&batch));
+        downstream.set_registry(f.registry());
+
+        let mut tampered = batch.clone();
+        tampered.payloads[1].message[0] ^= 1;
+        assert_eq!(Err(DeliveryError::InvalidRoot(0)), downstream.receive(&tampered));
+
+        let mut forged = batch.clone();
+        forged.exceptions.clear();
+        forged.bitmap[1] = true;
+        assert_eq!(Err(DeliveryError::InvalidSignature(0)), downstream.receive(&forged));
+
+        let mut other_broker = batch.clone();
+        other_broker.context.broker_id = 1;
+        assert!(matches!(downstream.receive(&other_broker), Err(DeliveryError::WrongBroker { batch_id: 0, broker_id: 1 })));
+
+        /*refused batches are not delivered, so the batch still goes through, but only once */
+        assert_eq!(None, downstream.last_delivered());
+        assert_eq!(Ok(3), downstream.receive(&received[0]));
+        assert_eq!(Err(DeliveryError::OutOfOrder { batch_id: 0, last_delivered: 0 }), downstream.receive(&received[0]));
+        assert_eq!((1, Some(0)), (downstream.delivered(), downstream.last_delivered()));
+
+        drop(downstream);
+        assert_eq!(vec![(0,0),(0,1),(0,2)], delivered);
+    }
+
+    #[test]
+    fn test_downstream_refuses_replayed_batches() {
+        let mut f = Fixture::new(3, BatchConfig { batch_size: 3, ..BatchConfig::default() });
+        let first = distill(&mut f, 0, &[0,2]);
+        let second = distill(&mut f, 1, &[1]);
+        assert_eq!((0,1), (first.context.batch_id, second.context.batch_id));
+
+        /*the broker sends the same batch twice in its stream */
+        let mut stream = Vec::new();
+        let mut sender = BatchSender::new(&mut stream).unwrap();
+        for batch in [&first, &first, &second] {
+            sender.send(batch).unwrap();
+        }
+        let received: Vec<LoggedBatch> = BatchLogReader::from_reader(&stream[..]).unwrap().map(|r| r.unwrap()).collect();
+
+        let mut delivered = 0;
+        let mut downstream = Downstream::new(0, f.registry(), |_, _: &Payload| delivered += 1);
+        assert_eq!(Ok(3), downstream.receive(&received[0]));
+        assert_eq!(Err(DeliveryError::OutOfOrder { batch_id: 0, last_delivered: 0 }), downstream.receive(&received[1]));
+        assert_eq!(Ok(3), downstream.receive(&received[2]));
+        /*an older batch can't come back after a newer one either */
+        assert_eq!(Err(DeliveryError::OutOfOrder { batch_id: 0, last_delivered: 1 }), downstream.receive(&first));
+        assert_eq!((2, Some(1)), (downstream.delivered(), downstream.last_delivered()));
+
+        drop(downstream);
+        assert_eq!(6, delivered);
+    }
+
+    #[test]
+    fn test_downstream_refuses_replayed_payloads() {
+        let mut f = Fixture::new(3, BatchConfig { batch_size: 3, ..BatchConfig::default() });
+        let first = distill(&mut f, 0, &[0,2]);
+
+        /*the broker puts the payload of client 1 it delivered in the first batch back into the
+        next one, where it is an exception again since its signature does not depend on the batch */
+        let mut sealed = None;
+        for (client_id, seq_num) in [(0, 1), (1, 0), (2, 1)] {
+            sealed = f.submit(client_id, seq_num, vec![client_id as u8;4]).unwrap().2;
+        }
+        let sealed = sealed.expect("batch should be sealed");
+        for pos in [0,2] {
+            f.sign(&sealed, pos).unwrap();
+        }
+        f.manager.proposal_to_distilled(1).unwrap();
+        let replayed = LoggedBatch::from_distilled(&f.manager.drain_distilled()[0]);
+        assert_eq!(first.payloads[1], replayed.payloads[1]);
+        assert!(replayed.verify(&f.registry()));
+
+        let mut delivered = Vec::new();
+        let mut downstream = Downstream::new(0, f.registry(), |_, payload: &Payload| delivered.push((payload.num_id, payload.seq_num)));
+        assert_eq!(Ok(3), downstream.receive(&first));
+        assert_eq!(Err(DeliveryError::StaleSequenceNumber { batch_id: 1, client_id: 1, seq_num: 0, last_delivered: 0 }), downstream.receive(&replayed));
+
+        /*the payloads of the refused batch were not delivered, the next ones of their clients go through */
+        assert_eq!(Ok(3), downstream.receive(&distill(&mut f, 1, &[0,1])));
+        drop(downstream);
+        assert_eq!(vec![(0,0),(1,0),(2,0),(0,1),(1,1),(2,1)], delivered);
+    }
 }
@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use libc::{sockaddr_in, AF_INET};
+
+use crate::batch::{BatchId, NumericalIdentifier, PositionInBatch, Submission};
+use crate::batch_log::{check_header, frame, init_log, read_frame, LogError};
+use crate::codec::Cursor;
+
+/// First bytes of every write-ahead log of the broker
+const WAL_TAG: &[u8;16] = b"RAINFALL_BRKRWAL";
+const WAL_VERSION: u8 = 1;
+
+const ASSIGNED: u8 = 0;
+const SEALED: u8 = 1;
+
+/// Bound on the body of a record: an assignment holds a single submission
+const MAX_RECORD_LEN: u64 = 1 << 20;
+/// The log is compacted once it holds this many records more than the batches in flight need
+pub(crate) const COMPACT_AFTER: usize = 64;
+
+/// What the broker writes ahead of acting on it, so that the batches it has in flight
+/// can be rebuilt after a restart (see `recover`)
+#[derive(Debug,Clone)]
+pub enum WalRecord {
+    /// The submission of the client was added at `pos` in the batch in construction
+    Assigned {
+        batch_id: BatchId,
+        pos: PositionInBatch,
+        client_id: NumericalIdentifier,
+        addr: sockaddr_in,
+        submission: Box<Submission>,
+    },
+    /// The batch became a proposal. Every assignment of the batch comes before it in the log.
+    Sealed { batch_id: BatchId },
+}
+
+impl WalRecord {
+    pub fn get_batch_id(&self) -> BatchId {
+        match self {
+            WalRecord::Assigned { batch_id, .. } | WalRecord::Sealed { batch_id } => *batch_id,
+        }
+    }
+
+    /*The body of the record (see `frame`) starts with its kind. An assignment follows with the
+    batch id, the position and the client id (8 bytes each, big endian), the IPv4 address and
+    the port of the client (4 and 2 bytes, in network order as in the sockaddr_in) and the
+    submission. A seal follows with the batch id. */
+    fn to_body(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            WalRecord::Assigned { batch_id, pos, client_id, addr, submission } => {
+                body.push(ASSIGNED);
+                body.extend_from_slice(&(*batch_id as u64).to_be_bytes());
+                body.extend_from_slice(&(*pos as u64).to_be_bytes());
+                body.extend_from_slice(&client_id.to_be_bytes());
+                body.extend_from_slice(&addr.sin_addr.s_addr.to_ne_bytes());
+                body.extend_from_slice(&addr.sin_port.to_ne_bytes());
+                body.extend_from_slice(&submission.to_bytes());
+            },
+            WalRecord::Sealed { batch_id } => {
+                body.push(SEALED);
+                body.extend_from_slice(&(*batch_id as u64).to_be_bytes());
+            },
+        }
+        body
+    }
+
+    fn from_body(body: &[u8]) -> Option<Self> {
+        let mut cursor = Cursor::new(body);
+        let kind = cursor.take(1)?[0];
+        let batch_id = cursor.take_u64()? as BatchId;
+
+        match kind {
+            ASSIGNED => {
+                let pos = cursor.take_u64()? as PositionInBatch;
+                let client_id = cursor.take_u64()?;
+
+                let mut addr: sockaddr_in = unsafe { mem::zeroed() };
+                addr.sin_family = AF_INET as u16;
+                addr.sin_addr.s_addr = u32::from_ne_bytes(cursor.take(4)?.try_into().ok()?);
+                addr.sin_port = u16::from_ne_bytes(cursor.take(2)?.try_into().ok()?);
+
+                let submission = Box::new(Submission::from_bytes(cursor.rest()).ok()?);
+                Some(WalRecord::Assigned { batch_id, pos, client_id, addr, submission })
+            },
+            SEALED if cursor.is_done() => Some(WalRecord::Sealed { batch_id }),
+            _ => None,
+        }
+    }
+}
+
+/// Write-ahead log of the assignments of the clients to batches and of the seals.
+/// It only has to hold the batches that are not in the batch log yet: the records of a
+/// batch are dropped once it is `delivered`, and the log is rewritten with the batches
+/// still in flight whenever it holds too many records of delivered ones.
+///
+/// A record survives a crash, power loss included, once the call that synced it returned:
+/// `append` for a seal, `flush` for the assignments appended before it. The broker flushes
+/// after each burst of packets, before the packets of the next one are handled.
+#[derive(Debug)]
+pub struct BatchWal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// The records of the batches not delivered yet, which a compaction keeps
+    in_flight: BTreeMap<BatchId,Vec<WalRecord>>,
+    /// Number of records in the file
+    written: usize,
+}
+
+impl BatchWal {
+    /// Opens the log for appending, creating it if it does not exist yet. The log must be
+    /// whole: after a crash, it is opened by `recover`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self,LogError> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        init_log(&mut file, WAL_TAG, WAL_VERSION)?;
+
+        let mut wal = Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            in_flight: BTreeMap::new(),
+            written: 0,
+        };
+        for record in WalReader::open(path)? {
+            wal.keep(record?);
+        }
+        Ok(wal)
+    }
+
+    /// Replaces the log by one that only holds `records`. The new log is written next to the
+    /// old one and renamed over it, so that a crash in the middle leaves one or the other.
+    /// The directory is synced after the rename, otherwise the rename itself could be lost.
+    pub fn rewrite<P: AsRef<Path>>(path: P, records: &[WalRecord]) -> Result<Self,LogError> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            init_log(&mut file, WAL_TAG, WAL_VERSION)?;
+            for record in records {
+                file.write_all(&frame(&record.to_body()))?;
+            }
+            file.sync_data()?;
+        }
+        fs::rename(&tmp, path)?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        Self::open(path)
+    }
+
+    /// Assignments are buffered, they reach the disk on the next seal or `flush`.
+    /// A seal is on disk, along with every record before it, once this returns:
+    /// it has to be recorded before the proofs of inclusion of the batch are sent.
+    pub fn append(&mut self, record: &WalRecord) -> Result<(),LogError> {
+        self.writer.write_all(&frame(&record.to_body()))?;
+        if let WalRecord::Sealed { .. } = record {
+            self.writer.flush()?;
+            self.writer.get_ref().sync_data()?;
+        }
+        self.keep(record.clone());
+        Ok(())
+    }
+
+    /// Writes the buffered assignments and syncs them to disk
+    pub fn flush(&mut self) -> Result<(),LogError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Drops the records of a batch once it is in the batch log, and compacts the log
+    /// if it holds more than `COMPACT_AFTER` records of delivered batches.
+    pub fn delivered(&mut self, batch_id: BatchId) -> Result<(),LogError> {
+        self.in_flight.remove(&batch_id);
+        if self.written - self.records_in_flight() > COMPACT_AFTER {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log with only the records of the batches in flight
+    pub fn compact(&mut self) -> Result<(),LogError> {
+        self.flush()?;
+        let records: Vec<WalRecord> = self.in_flight.values().flatten().cloned().collect();
+        *self = Self::rewrite(&self.path, &records)?;
+        Ok(())
+    }
+
+    /// Number of records in the file, including the ones of delivered batches
+    pub fn len(&self) -> usize {
+        self.written
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.written == 0
+    }
+
+    fn records_in_flight(&self) -> usize {
+        self.in_flight.values().map(|records| records.len()).sum()
+    }
+
+    fn keep(&mut self, record: WalRecord) {
+        self.in_flight.entry(record.get_batch_id()).or_default().push(record);
+        self.written += 1;
+    }
+}
+
+/// Reads a write-ahead log from the start, checking the checksum of every record.
+/// The iteration stops after the first error, which is expected at the end of the
+/// log if the broker crashed while writing a record.
+#[derive(Debug)]
+pub struct WalReader {
+    reader: BufReader<File>,
+    record: usize,
+    done: bool,
+}
+
+impl WalReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self,LogError> {
+        let mut file = File::open(path)?;
+        check_header(&mut file, WAL_TAG, WAL_VERSION)?;
+        Ok(Self {
+            reader: BufReader::new(file),
+            record: 0,
+            done: false,
+        })
+    }
+
+    fn read_record(&mut self) -> Result<Option<WalRecord>,LogError> {
+        let record = self.record;
+        match read_frame(&mut self.reader, record, MAX_RECORD_LEN)? {
+            Some(body) => WalRecord::from_body(&body).map(Some).ok_or(LogError::Corrupted { record }),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Iterator for WalReader {
+    type Item = Result<WalRecord,LogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+
+        match self.read_record() {
+            Ok(Some(record)) => {
+                self.record += 1;
+                Some(Ok(record))
+            },
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}